@@ -0,0 +1,25 @@
+// Copies a texture into a render target, filtered. Used to fill in mip levels, see textures.rs
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+    // a single triangle covering the whole target
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+[[group(0), binding(0)]]
+var source_texture: texture_2d<f32>;
+[[group(0), binding(1)]]
+var source_sampler: sampler;
+
+[[stage(fragment)]]
+fn fs_main(vertex: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(source_texture, source_sampler, vertex.uv);
+}
@@ -1,6 +1,8 @@
 // Vertex shader
+// has to match CameraUniform in camera.rs
 struct CameraUniform {
-    matrix: mat4x4<f32>;
+    view_proj: mat4x4<f32>;
+    view_position: vec4<f32>;
 };
 [[group(1), binding(0)]]
 var<uniform> camera: CameraUniform;
@@ -16,12 +18,21 @@ struct InstanceInput {
     [[location(6)]] matrix_1: vec4<f32>;
     [[location(7)]] matrix_2: vec4<f32>;
     [[location(8)]] matrix_3: vec4<f32>;
+    [[location(9)]] normal_matrix_0: vec3<f32>;
+    [[location(10)]] normal_matrix_1: vec3<f32>;
+    [[location(11)]] normal_matrix_2: vec3<f32>;
+    [[location(12)]] tint: vec4<f32>;
+    [[location(13)]] data: vec4<f32>;
+    [[location(14)]] receive_shadows: f32;
 };
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] normal: vec3<f32>;
     [[location(1)]] uv: vec2<f32>;
+    [[location(2)]] world_position: vec3<f32>;
+    [[location(3)]] tint: vec4<f32>;
+    [[location(4)]] receive_shadows: f32;
 };
 
 [[stage(vertex)]]
@@ -36,25 +47,218 @@ fn vs_main(
         instance.matrix_2,
         instance.matrix_3,
     );
-    let model_view_proj = camera.matrix * instance_matrix;
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+    let world_position = instance_matrix * vec4<f32>(model.position, 1.0);
 
-    // transform normals to match the object's rotation
-    // note that this fails if any scaling is done, unless we use the inverse transpose of model_view_proj
-    out.normal = normalize((model_view_proj * vec4<f32>(model.normal, 0.0)).xyz);
+    // lighting happens in world space, so the fragment stage gets positions and normals that only
+    // went through the instance's transform. the camera's view_proj is only used for clip_position,
+    // otherwise the lighting would change whenever the camera moves.
+    // normal_matrix is the inverse transpose of the instance matrix, so non-uniform scaling doesn't skew them
+    out.normal = normalize(normal_matrix * model.normal);
     out.uv = model.uv;
-    out.clip_position = model_view_proj * vec4<f32>(model.position, 1.0);
+    out.world_position = world_position.xyz;
+    out.tint = instance.tint;
+    out.receive_shadows = instance.receive_shadows;
+    out.clip_position = camera.view_proj * world_position;
     
     return out;
 }
 
+// has to match MaterialUniform in material.rs
+struct MaterialUniform {
+    base_color_factor: vec4<f32>;
+    emissive_factor: vec4<f32>;
+    metallic_factor: f32;
+    roughness_factor: f32;
+    normal_scale: f32;
+    occlusion_strength: f32;
+};
+
 [[group(0), binding(0)]]
-var texture_view: texture_2d<f32>;
+var base_color_texture: texture_2d<f32>;
 [[group(0), binding(1)]]
-var texture_sampler: sampler;
+var base_color_sampler: sampler;
+[[group(0), binding(2)]]
+var<uniform> material: MaterialUniform;
+[[group(0), binding(3)]]
+var metallic_roughness_texture: texture_2d<f32>;
+[[group(0), binding(4)]]
+var metallic_roughness_sampler: sampler;
+[[group(0), binding(5)]]
+var normal_texture: texture_2d<f32>;
+[[group(0), binding(6)]]
+var normal_sampler: sampler;
+[[group(0), binding(7)]]
+var occlusion_texture: texture_2d<f32>;
+[[group(0), binding(8)]]
+var occlusion_sampler: sampler;
+[[group(0), binding(9)]]
+var emissive_texture: texture_2d<f32>;
+[[group(0), binding(10)]]
+var emissive_sampler: sampler;
+
+// has to match RawLight and LightsUniform in lights.rs
+struct Light {
+    position: vec4<f32>; // w is the kind: 0 directional, 1 point, 2 spot
+    direction: vec4<f32>; // w is the range
+    color: vec4<f32>;
+    cone: vec4<f32>; // cosines of the inner and outer angle, then the shadow map index or -1
+};
+
+struct LightsUniform {
+    ambient: vec4<f32>;
+    count: u32;
+    lights: array<Light, 16>;
+    shadow_matrices: array<mat4x4<f32>, 4>;
+};
+[[group(2), binding(0)]]
+var<uniform> lights: LightsUniform;
+[[group(2), binding(1)]]
+var shadow_maps: texture_depth_2d_array;
+[[group(2), binding(2)]]
+var shadow_sampler: sampler_comparison;
+
+// has to match SHADOW_MAP_SIZE in shadows.rs
+let SHADOW_MAP_SIZE: f32 = 2048.0;
+// how far points are pushed along their normal before looking them up, against shadow acne
+let SHADOW_NORMAL_OFFSET: f32 = 0.02;
+
+let PI: f32 = 3.14159265359;
+let MIN_LIGHT_DISTANCE: f32 = 0.0001;
+
+// GGX / Trowbridge-Reitz normal distribution
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// smith's method with schlick-GGX for both the view and the light direction
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let view = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let light = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return view * light;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    let one = vec3<f32>(1.0, 1.0, 1.0);
+    return f0 + (one - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// how much of the light reaches the point, from 0 (fully shadowed) to 1.
+// averages a 3x3 block of shadow map comparisons (PCF) for soft edges
+fn shadow_factor(shadow_map: i32, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (shadow_map < 0) {
+        return 1.0;
+    }
+    let light_space = lights.shadow_matrices[shadow_map] * vec4<f32>(world_position + normal * SHADOW_NORMAL_OFFSET, 1.0);
+    if (light_space.w <= 0.0) {
+        return 1.0;
+    }
+    let position = light_space.xyz / light_space.w;
+    // anything outside the shadow map is lit
+    if (position.x < -1.0 || position.x > 1.0 || position.y < -1.0 || position.y > 1.0 || position.z > 1.0) {
+        return 1.0;
+    }
+    let uv = vec2<f32>(position.x * 0.5 + 0.5, 0.5 - position.y * 0.5);
+    let texel = 1.0 / SHADOW_MAP_SIZE;
+    var lit = 0.0;
+    for (var y: i32 = -1; y <= 1; y = y + 1) {
+        for (var x: i32 = -1; x <= 1; x = x + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel;
+            lit = lit + textureSampleCompareLevel(shadow_maps, shadow_sampler, uv + offset, shadow_map, position.z);
+        }
+    }
+    return lit / 9.0;
+}
+
+// meshes don't have tangents, so the tangent frame for normal mapping is
+// built from screen-space derivatives of the position and uv instead
+fn perturb_normal(normal: vec3<f32>, world_position: vec3<f32>, uv: vec2<f32>, tangent_normal: vec3<f32>) -> vec3<f32> {
+    let dp1 = dpdx(world_position);
+    let dp2 = dpdy(world_position);
+    let duv1 = dpdx(uv);
+    let duv2 = dpdy(uv);
+    let dp2_perp = cross(dp2, normal);
+    let dp1_perp = cross(normal, dp1);
+    let tangent = dp2_perp * duv1.x + dp1_perp * duv2.x;
+    let bitangent = dp2_perp * duv1.y + dp1_perp * duv2.y;
+    let scale = max(dot(tangent, tangent), dot(bitangent, bitangent));
+    if (scale < 0.0000000001) {
+        // no usable uvs, so there's no frame to map into
+        return normal;
+    }
+    let inverse_length = inverseSqrt(scale);
+    let tbn = mat3x3<f32>(tangent * inverse_length, bitangent * inverse_length, normal);
+    return normalize(tbn * tangent_normal);
+}
 
 [[stage(fragment)]]
 fn fs_main(model: VertexOutput) -> [[location(0)]] vec4<f32> {
-    var lightDirection = normalize(vec3<f32>(0.5, -1.0, 0.5));
-    var diffuseIntensity = dot(model.normal, lightDirection);
-    return textureSample(texture_view, texture_sampler, model.uv) * max(0.05, diffuseIntensity);
-}
\ No newline at end of file
+    // every texture is sampled up front, sampling has to happen in uniform control flow
+    let base_color = textureSample(base_color_texture, base_color_sampler, model.uv) * material.base_color_factor * model.tint;
+    let metallic_roughness = textureSample(metallic_roughness_texture, metallic_roughness_sampler, model.uv);
+    let sampled_normal = textureSample(normal_texture, normal_sampler, model.uv).xyz * 2.0 - vec3<f32>(1.0, 1.0, 1.0);
+    let occlusion_sample = textureSample(occlusion_texture, occlusion_sampler, model.uv).r;
+    let emissive = textureSample(emissive_texture, emissive_sampler, model.uv).rgb * material.emissive_factor.rgb;
+
+    // like glTF: roughness in green, metalness in blue
+    let metallic = clamp(material.metallic_factor * metallic_roughness.b, 0.0, 1.0);
+    let roughness = clamp(material.roughness_factor * metallic_roughness.g, 0.04, 1.0);
+    let occlusion = 1.0 + material.occlusion_strength * (occlusion_sample - 1.0);
+    let tangent_normal = normalize(vec3<f32>(sampled_normal.xy * material.normal_scale, sampled_normal.z));
+
+    let normal = perturb_normal(normalize(model.normal), model.world_position, model.uv, tangent_normal);
+    let view_direction = normalize(camera.view_position.xyz - model.world_position);
+    let n_dot_v = max(dot(normal, view_direction), 0.0001);
+    // dielectrics reflect about 4% head on, metals reflect their own color
+    let f0 = mix(vec3<f32>(0.04, 0.04, 0.04), base_color.rgb, vec3<f32>(metallic, metallic, metallic));
+
+    var radiance_out = vec3<f32>(0.0, 0.0, 0.0);
+    for (var i: u32 = 0u; i < lights.count; i = i + 1u) {
+        let light = lights.lights[i];
+        var light_direction = -light.direction.xyz;
+        var attenuation = 1.0;
+        if (light.position.w > 0.5) {
+            // point and spot lights fade out smoothly, reaching zero at their range
+            let to_light = light.position.xyz - model.world_position;
+            // a light right on the surface would divide by zero
+            let distance = max(length(to_light), MIN_LIGHT_DISTANCE);
+            light_direction = to_light / distance;
+            let falloff = clamp(1.0 - pow(distance / light.direction.w, 4.0), 0.0, 1.0);
+            attenuation = falloff * falloff / (1.0 + distance * distance);
+            if (light.position.w > 1.5) {
+                let cos_angle = dot(-light_direction, light.direction.xyz);
+                // smoothstep from the outer to the inner cosine, which as_raw keeps apart
+                let t = clamp((cos_angle - light.cone.y) / (light.cone.x - light.cone.y), 0.0, 1.0);
+                attenuation = attenuation * t * t * (3.0 - 2.0 * t);
+            }
+        }
+
+        if (model.receive_shadows > 0.5) {
+            attenuation = attenuation * shadow_factor(i32(light.cone.z), model.world_position, normalize(model.normal));
+        }
+
+        // cook-torrance
+        let half_direction = normalize(light_direction + view_direction);
+        let n_dot_l = max(dot(normal, light_direction), 0.0);
+        let n_dot_h = max(dot(normal, half_direction), 0.0);
+        let d = distribution_ggx(n_dot_h, roughness);
+        let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+        let f = fresnel_schlick(max(dot(half_direction, view_direction), 0.0), f0);
+        let specular = f * (d * g / (4.0 * n_dot_v * n_dot_l + 0.0001));
+        // whatever is reflected doesn't get diffused, and metals don't diffuse at all
+        let diffuse = (vec3<f32>(1.0, 1.0, 1.0) - f) * (1.0 - metallic) * base_color.rgb / PI;
+        radiance_out = radiance_out + (diffuse + specular) * light.color.rgb * attenuation * n_dot_l;
+    }
+
+    let ambient = lights.ambient.rgb * base_color.rgb * occlusion;
+    return vec4<f32>(ambient + radiance_out + emissive, base_color.a);
+}
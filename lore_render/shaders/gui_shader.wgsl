@@ -1,6 +1,7 @@
 // Vertex shader
 struct CameraUniform {
-    matrix: mat4x4<f32>;
+    view_proj: mat4x4<f32>;
+    view_position: vec4<f32>;
 };
 [[group(1), binding(0)]]
 var<uniform> camera: CameraUniform;
@@ -16,12 +17,14 @@ struct InstanceInput {
     [[location(6)]] matrix_1: vec4<f32>;
     [[location(7)]] matrix_2: vec4<f32>;
     [[location(8)]] matrix_3: vec4<f32>;
+    [[location(12)]] tint: vec4<f32>;
 };
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] normal: vec3<f32>;
     [[location(1)]] uv: vec2<f32>;
+    [[location(2)]] tint: vec4<f32>;
 };
 
 [[stage(vertex)]]
@@ -37,10 +40,10 @@ fn vs_main(
         instance.matrix_3,
     );
 
-    // transform normals to match the object's rotation
-    // note that this fails if any scaling is done, unless we use the inverse transpose of model_view_proj
+    // gui elements are placed directly in clip space and aren't lit, so the normal is passed through untouched
     out.normal = model.normal;
     out.uv = model.uv;
+    out.tint = instance.tint;
     out.clip_position = instance_matrix * vec4<f32>(model.position, 1.0);
     
     return out;
@@ -53,5 +56,5 @@ var texture_sampler: sampler;
 
 [[stage(fragment)]]
 fn fs_main(model: VertexOutput) -> [[location(0)]] vec4<f32> {
-    return textureSample(texture_view, texture_sampler, model.uv);
+    return textureSample(texture_view, texture_sampler, model.uv) * model.tint;
 }
\ No newline at end of file
@@ -0,0 +1,31 @@
+// Depth-only pass rendering shadow casters into a shadow map, see shadows.rs
+struct ShadowPassUniform {
+    light_matrix: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> pass: ShadowPassUniform;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] matrix_0: vec4<f32>;
+    [[location(6)]] matrix_1: vec4<f32>;
+    [[location(7)]] matrix_2: vec4<f32>;
+    [[location(8)]] matrix_3: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> [[builtin(position)]] vec4<f32> {
+    let instance_matrix = mat4x4<f32>(
+        instance.matrix_0,
+        instance.matrix_1,
+        instance.matrix_2,
+        instance.matrix_3,
+    );
+    return pass.light_matrix * instance_matrix * vec4<f32>(model.position, 1.0);
+}
@@ -0,0 +1,588 @@
+/*!
+ * decompressing BC and ETC2/EAC blocks on the CPU, for GPUs that can't sample
+ * the format. every block is 4x4 pixels, and decodes to RGBA8 in row order.
+ *
+ * single and dual channel formats decode into red and green, with blue at 0 and
+ * alpha at 255, which is what the GPU would have sampled from them.
+ *
+ * BC is described at https://learn.microsoft.com/en-us/windows/win32/direct3d11/texture-block-compression-in-direct3d-11
+ * and ETC2/EAC in appendix C of the OpenGL ES 3.0 specification.
+ */
+
+use crate::asset_loading::ktx2::BlockFormat;
+
+pub(super) type Block = [[u8; 4]; 16];
+
+/// Decodes one block of `format`, which must be `format.bytes_per_block()` long
+pub(super) fn decode_block(format: BlockFormat, data: &[u8]) -> Block {
+    let mut pixels = [[0, 0, 0, 255]; 16];
+    match format {
+        BlockFormat::Bc1 => decode_bc1(&data[0..8], false, &mut pixels),
+        BlockFormat::Bc3 => {
+            decode_bc1(&data[8..16], true, &mut pixels);
+            decode_bc4(&data[0..8], 3, &mut pixels);
+        }
+        BlockFormat::Bc4 => decode_bc4(&data[0..8], 0, &mut pixels),
+        BlockFormat::Bc5 => {
+            decode_bc4(&data[0..8], 0, &mut pixels);
+            decode_bc4(&data[8..16], 1, &mut pixels);
+        }
+        BlockFormat::Bc7 => decode_bc7(data, &mut pixels),
+        BlockFormat::Etc2Rgb8 => decode_etc2(&data[0..8], &mut pixels),
+        BlockFormat::Etc2Rgba8 => {
+            decode_etc2(&data[8..16], &mut pixels);
+            decode_eac(&data[0..8], 3, false, &mut pixels);
+        }
+        BlockFormat::EacR11 => decode_eac(&data[0..8], 0, true, &mut pixels),
+        BlockFormat::EacRg11 => {
+            decode_eac(&data[0..8], 0, true, &mut pixels);
+            decode_eac(&data[8..16], 1, true, &mut pixels);
+        }
+    }
+    pixels
+}
+
+// -- BC1-5 --
+
+fn rgb565(color: u16) -> [u8; 3] {
+    let (r, g, b) = ((color >> 11) as u8, (color >> 5 & 0x3F) as u8, (color & 0x1F) as u8);
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+// BC2 and BC3 always use four colors, whatever order the endpoints are in
+fn decode_bc1(data: &[u8], always_four_colors: bool, pixels: &mut Block) {
+    let c0 = u16::from_le_bytes([data[0], data[1]]);
+    let c1 = u16::from_le_bytes([data[2], data[3]]);
+    let (e0, e1) = (rgb565(c0), rgb565(c1));
+    let mix = |a: u8, b: u8, wa: u32, wb: u32| ((a as u32 * wa + b as u32 * wb) / (wa + wb)) as u8;
+    let mut palette = [[e0[0], e0[1], e0[2], 255], [e1[0], e1[1], e1[2], 255], [0; 4], [0; 4]];
+    for channel in 0..3 {
+        if c0 > c1 || always_four_colors {
+            palette[2][channel] = mix(e0[channel], e1[channel], 2, 1);
+            palette[3][channel] = mix(e0[channel], e1[channel], 1, 2);
+        } else {
+            palette[2][channel] = mix(e0[channel], e1[channel], 1, 1);
+        }
+    }
+    palette[2][3] = 255;
+    // the fourth color is transparent black in three color mode
+    if c0 > c1 || always_four_colors {
+        palette[3][3] = 255;
+    }
+
+    let indices = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let color = palette[(indices >> (i * 2) & 3) as usize];
+        pixel[..3].copy_from_slice(&color[..3]);
+        pixel[3] = color[3];
+    }
+}
+
+// a single channel block, written into `channel`
+fn decode_bc4(data: &[u8], channel: usize, pixels: &mut Block) {
+    let (r0, r1) = (data[0] as u32, data[1] as u32);
+    let mut palette = [0u8; 8];
+    palette[0] = r0 as u8;
+    palette[1] = r1 as u8;
+    if r0 > r1 {
+        for (i, value) in palette.iter_mut().enumerate().skip(2) {
+            *value = (((8 - i as u32) * r0 + (i as u32 - 1) * r1) / 7) as u8;
+        }
+    } else {
+        for (i, value) in palette.iter_mut().enumerate().take(6).skip(2) {
+            *value = (((6 - i as u32) * r0 + (i as u32 - 1) * r1) / 5) as u8;
+        }
+        palette[6] = 0;
+        palette[7] = 255;
+    }
+
+    let indices = data[2..8].iter().rev().fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        pixel[channel] = palette[(indices >> (i * 3) & 7) as usize];
+    }
+}
+
+// -- BC7 --
+
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    endpoint_p_bits: bool,
+    shared_p_bits: bool,
+    index_bits: u32,
+    secondary_index_bits: u32,
+}
+
+const BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode { subsets: 3, partition_bits: 4, rotation_bits: 0, index_selection_bits: 0, color_bits: 4, alpha_bits: 0, endpoint_p_bits: true, shared_p_bits: false, index_bits: 3, secondary_index_bits: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 6, alpha_bits: 0, endpoint_p_bits: false, shared_p_bits: true, index_bits: 3, secondary_index_bits: 0 },
+    Bc7Mode { subsets: 3, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 0, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, secondary_index_bits: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 0, endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, secondary_index_bits: 0 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 1, color_bits: 5, alpha_bits: 6, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, secondary_index_bits: 3 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 0, color_bits: 7, alpha_bits: 8, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, secondary_index_bits: 2 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 7, endpoint_p_bits: true, shared_p_bits: false, index_bits: 4, secondary_index_bits: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 5, endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, secondary_index_bits: 0 },
+];
+
+// which subset every pixel is in, one bit per pixel
+const BC7_PARTITIONS_2: [u16; 64] = [
+    0xCCCC, 0x8888, 0xEEEE, 0xECC8, 0xC880, 0xFEEC, 0xFEC8, 0xEC80,
+    0xC800, 0xFFEC, 0xFE80, 0xE800, 0xFFE8, 0xFF00, 0xFFF0, 0xF000,
+    0xF710, 0x008E, 0x7100, 0x08CE, 0x008C, 0x7310, 0x3100, 0x8CCE,
+    0x088C, 0x3110, 0x6666, 0x366C, 0x17E8, 0x0FF0, 0x718E, 0x399C,
+    0xAAAA, 0xF0F0, 0x5A5A, 0x33CC, 0x3C3C, 0x55AA, 0x9696, 0xA55A,
+    0x73CE, 0x13C8, 0x324C, 0x3BDC, 0x6996, 0xC33C, 0x9966, 0x0660,
+    0x0272, 0x04E4, 0x4E40, 0x2720, 0xC936, 0x936C, 0x39C6, 0x639C,
+    0x9336, 0x9CC6, 0x817E, 0xE718, 0xCCF0, 0x0FCC, 0x7744, 0xEE22,
+];
+
+// two bits per pixel
+const BC7_PARTITIONS_3: [u32; 64] = [
+    0xAA685050, 0x6A5A5040, 0x5A5A4200, 0x5450A0A8, 0xA5A50000, 0xA0A05050, 0x5555A0A0, 0x5A5A5050,
+    0xAA550000, 0xAA555500, 0xAAAA5500, 0x90909090, 0x94949494, 0xA4A4A4A4, 0xA9A59450, 0x2A0A4250,
+    0xA5945040, 0x0A425054, 0xA5A5A500, 0x55A0A0A0, 0xA8A85454, 0x6A6A4040, 0xA4A45000, 0x1A1A0500,
+    0x0050A4A4, 0xAAA59090, 0x14696914, 0x69691400, 0xA08585A0, 0xAA821414, 0x50A4A450, 0x6A5A0200,
+    0xA9A58000, 0x5090A0A8, 0xA8A09050, 0x24242424, 0x00AA5500, 0x24924924, 0x24499224, 0x50A50A50,
+    0x500AA550, 0xAAAA4444, 0x66660000, 0xA5A0A5A0, 0x50A050A0, 0x69286928, 0x44AAAA44, 0x66666600,
+    0xAA444444, 0x54A854A8, 0x95809580, 0x96969600, 0xA85454A8, 0x80959580, 0xAA141414, 0x96960000,
+    0xAAAA1414, 0xA05050A0, 0xA0A5A5A0, 0x96000000, 0x40804080, 0xA9A8A9A8, 0xAAAAAA44, 0x2A4A5254,
+];
+
+// the pixel of each subset (besides the first) whose index is a bit shorter
+const BC7_ANCHORS_2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15, 2, 8, 2, 2, 8, 8, 15, 2, 8, 2, 2, 8, 8, 2, 2,
+    15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6,
+    6, 2, 6, 8, 15, 15, 2, 2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+
+const BC7_ANCHORS_3_SECOND: [u8; 64] = [
+    3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3,
+    3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5, 15, 15,
+    8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15,
+    3, 15, 5, 5, 5, 8, 5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+];
+
+const BC7_ANCHORS_3_THIRD: [u8; 64] = [
+    15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8,
+    15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6, 10, 15, 15, 10, 8,
+    15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8,
+    15, 3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+];
+
+const BC7_WEIGHTS_2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS_3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS_4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+fn bc7_subset(subsets: usize, partition: usize, pixel: usize) -> usize {
+    match subsets {
+        2 => (BC7_PARTITIONS_2[partition] >> pixel & 1) as usize,
+        3 => (BC7_PARTITIONS_3[partition] >> (pixel * 2) & 3) as usize,
+        _ => 0,
+    }
+}
+
+fn bc7_is_anchor(subsets: usize, partition: usize, pixel: usize) -> bool {
+    pixel == 0 || match subsets {
+        2 => pixel == BC7_ANCHORS_2[partition] as usize,
+        3 => pixel == BC7_ANCHORS_3_SECOND[partition] as usize || pixel == BC7_ANCHORS_3_THIRD[partition] as usize,
+        _ => false,
+    }
+}
+
+fn bc7_interpolate(e0: u8, e1: u8, index: u32, index_bits: u32) -> u8 {
+    let weight = match index_bits {
+        2 => BC7_WEIGHTS_2[index as usize],
+        3 => BC7_WEIGHTS_3[index as usize],
+        _ => BC7_WEIGHTS_4[index as usize],
+    };
+    (((64 - weight) * e0 as u32 + weight * e1 as u32 + 32) >> 6) as u8
+}
+
+// reads the block's bits from the lowest up
+struct BitReader {
+    bits: u128,
+}
+
+impl BitReader {
+    fn read(&mut self, count: u32) -> u32 {
+        let value = (self.bits & ((1u128 << count) - 1)) as u32;
+        self.bits >>= count;
+        value
+    }
+}
+
+fn decode_bc7(data: &[u8], pixels: &mut Block) {
+    let mut block = [0; 16];
+    block.copy_from_slice(&data[0..16]);
+    let mut bits = BitReader { bits: u128::from_le_bytes(block) };
+    let mode_number = block[0].trailing_zeros() as usize;
+    // a block without a mode is reserved, and decodes to transparent black
+    let mode = match BC7_MODES.get(mode_number) {
+        Some(mode) => mode,
+        None => {
+            *pixels = [[0; 4]; 16];
+            return;
+        }
+    };
+    bits.read(mode_number as u32 + 1);
+    let partition = bits.read(mode.partition_bits) as usize;
+    let rotation = bits.read(mode.rotation_bits);
+    let index_selection = bits.read(mode.index_selection_bits);
+
+    // two endpoints per subset, channel by channel
+    let mut endpoints = [[0u32; 4]; 6];
+    let endpoint_count = mode.subsets * 2;
+    for channel in 0..3 {
+        for endpoint in &mut endpoints[..endpoint_count] {
+            endpoint[channel] = bits.read(mode.color_bits);
+        }
+    }
+    for endpoint in &mut endpoints[..endpoint_count] {
+        endpoint[3] = bits.read(mode.alpha_bits);
+    }
+    let mut p_bits = [0u32; 6];
+    if mode.endpoint_p_bits {
+        for p_bit in &mut p_bits[..endpoint_count] {
+            *p_bit = bits.read(1);
+        }
+    } else if mode.shared_p_bits {
+        for subset in 0..mode.subsets {
+            let p_bit = bits.read(1);
+            p_bits[subset * 2] = p_bit;
+            p_bits[subset * 2 + 1] = p_bit;
+        }
+    }
+
+    // the p-bit is the lowest bit of every channel, then everything is scaled up to 8 bits
+    let has_p_bits = mode.endpoint_p_bits || mode.shared_p_bits;
+    let expand = |value: u32, bits: u32, p_bit: u32| -> u8 {
+        let (value, bits) = if has_p_bits { (value << 1 | p_bit, bits + 1) } else { (value, bits) };
+        let value = value << (8 - bits);
+        (value | value >> bits) as u8
+    };
+    let mut colors = [[0u8; 4]; 6];
+    for (i, color) in colors[..endpoint_count].iter_mut().enumerate() {
+        for channel in 0..3 {
+            color[channel] = expand(endpoints[i][channel], mode.color_bits, p_bits[i]);
+        }
+        color[3] = if mode.alpha_bits == 0 {
+            255
+        } else {
+            expand(endpoints[i][3], mode.alpha_bits, p_bits[i])
+        };
+    }
+
+    // anchor pixels have their highest index bit left out, it's always 0
+    let mut indices = [0u32; 16];
+    for (pixel, index) in indices.iter_mut().enumerate() {
+        let anchor = bc7_is_anchor(mode.subsets, partition, pixel);
+        *index = bits.read(mode.index_bits - anchor as u32);
+    }
+    let mut secondary_indices = [0u32; 16];
+    if mode.secondary_index_bits > 0 {
+        for (pixel, index) in secondary_indices.iter_mut().enumerate() {
+            *index = bits.read(mode.secondary_index_bits - (pixel == 0) as u32);
+        }
+    }
+
+    for (pixel, out) in pixels.iter_mut().enumerate() {
+        let subset = bc7_subset(mode.subsets, partition, pixel);
+        let (e0, e1) = (colors[subset * 2], colors[subset * 2 + 1]);
+        let (color_index, color_bits, alpha_index, alpha_bits) = if mode.secondary_index_bits == 0 {
+            (indices[pixel], mode.index_bits, indices[pixel], mode.index_bits)
+        } else if index_selection == 0 {
+            (indices[pixel], mode.index_bits, secondary_indices[pixel], mode.secondary_index_bits)
+        } else {
+            (secondary_indices[pixel], mode.secondary_index_bits, indices[pixel], mode.index_bits)
+        };
+        for channel in 0..3 {
+            out[channel] = bc7_interpolate(e0[channel], e1[channel], color_index, color_bits);
+        }
+        out[3] = bc7_interpolate(e0[3], e1[3], alpha_index, alpha_bits);
+        // rotation swaps alpha with one of the colors
+        if rotation > 0 {
+            out.swap(3, rotation as usize - 1);
+        }
+    }
+}
+
+// -- ETC2 and EAC --
+
+const ETC_MODIFIERS: [[i32; 2]; 8] = [[2, 8], [5, 17], [9, 29], [13, 42], [18, 60], [24, 80], [33, 106], [47, 183]];
+const ETC_DISTANCES: [i32; 8] = [3, 6, 11, 16, 23, 32, 41, 64];
+
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+    [-3, -6, -9, -15, 2, 5, 8, 14],
+    [-3, -7, -10, -13, 2, 6, 9, 12],
+    [-2, -5, -8, -13, 1, 4, 7, 12],
+    [-2, -4, -6, -13, 1, 3, 5, 12],
+    [-3, -6, -8, -12, 2, 5, 7, 11],
+    [-3, -7, -9, -11, 2, 6, 8, 10],
+    [-4, -7, -8, -11, 3, 6, 7, 10],
+    [-3, -5, -8, -11, 2, 4, 7, 10],
+    [-2, -6, -8, -10, 1, 5, 7, 9],
+    [-2, -5, -8, -10, 1, 4, 7, 9],
+    [-2, -4, -8, -10, 1, 3, 7, 9],
+    [-2, -5, -7, -10, 1, 4, 6, 9],
+    [-3, -4, -7, -10, 2, 3, 6, 9],
+    [-1, -2, -3, -10, 0, 1, 2, 9],
+    [-4, -6, -8, -9, 3, 5, 7, 8],
+    [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+fn extend_4(value: u8) -> i32 {
+    (value << 4 | value) as i32
+}
+
+fn extend_5(value: u8) -> i32 {
+    (value << 3 | value >> 2) as i32
+}
+
+fn extend_6(value: u8) -> i32 {
+    (value << 2 | value >> 4) as i32
+}
+
+fn extend_7(value: u8) -> i32 {
+    (value << 1 | value >> 6) as i32
+}
+
+fn clamp_u8(value: i32) -> u8 {
+    value.clamp(0, 255) as u8
+}
+
+fn offset_color(color: [i32; 3], offset: i32) -> [u8; 3] {
+    [clamp_u8(color[0] + offset), clamp_u8(color[1] + offset), clamp_u8(color[2] + offset)]
+}
+
+// ETC stores pixels column by column, and the rest here goes row by row
+fn etc_pixel(x: usize, y: usize) -> usize {
+    x * 4 + y
+}
+
+fn decode_etc2(data: &[u8], pixels: &mut Block) {
+    let (b0, b1, b2, b3) = (data[0], data[1], data[2], data[3]);
+    if b3 & 2 == 0 {
+        // individual mode, with two 4 bit colors
+        let c1 = [extend_4(b0 >> 4), extend_4(b1 >> 4), extend_4(b2 >> 4)];
+        let c2 = [extend_4(b0 & 0xF), extend_4(b1 & 0xF), extend_4(b2 & 0xF)];
+        return decode_etc1_subblocks(data, c1, c2, pixels);
+    }
+
+    // differential mode, with a 5 bit color and a signed 3 bit difference to the second
+    let base = [b0 >> 3, b1 >> 3, b2 >> 3];
+    let delta = |byte: u8| (((byte & 7) << 5) as i8 >> 5) as i32;
+    let second = [base[0] as i32 + delta(b0), base[1] as i32 + delta(b1), base[2] as i32 + delta(b2)];
+    let in_range = |value: i32| (0..32).contains(&value);
+    // colors out of range mean it's one of the modes ETC2 added
+    let paint = if !in_range(second[0]) {
+        // T mode
+        let c1 = [extend_4((b0 >> 3 & 3) << 2 | b0 & 3), extend_4(b1 >> 4), extend_4(b1 & 0xF)];
+        let c2 = [extend_4(b2 >> 4), extend_4(b2 & 0xF), extend_4(b3 >> 4)];
+        let distance = ETC_DISTANCES[((b3 >> 2 & 3) << 1 | b3 & 1) as usize];
+        [offset_color(c1, 0), offset_color(c2, distance), offset_color(c2, 0), offset_color(c2, -distance)]
+    } else if !in_range(second[1]) {
+        // H mode
+        let c1 = [
+            extend_4(b0 >> 3 & 0xF),
+            extend_4((b0 & 7) << 1 | b1 >> 4 & 1),
+            extend_4(b1 & 8 | (b1 & 3) << 1 | b2 >> 7),
+        ];
+        let c2 = [extend_4(b2 >> 3 & 0xF), extend_4((b2 & 7) << 1 | b3 >> 7), extend_4(b3 >> 3 & 0xF)];
+        // the lowest bit of the distance is in which of the colors is bigger
+        let bigger = (c1[0] << 16 | c1[1] << 8 | c1[2]) >= (c2[0] << 16 | c2[1] << 8 | c2[2]);
+        let distance = ETC_DISTANCES[(b3 & 4 | (b3 & 1) << 1 | bigger as u8) as usize];
+        [offset_color(c1, distance), offset_color(c1, -distance), offset_color(c2, distance), offset_color(c2, -distance)]
+    } else if !in_range(second[2]) {
+        return decode_etc2_planar(data, pixels);
+    } else {
+        let c1 = [extend_5(base[0]), extend_5(base[1]), extend_5(base[2])];
+        let c2 = [extend_5(second[0] as u8), extend_5(second[1] as u8), extend_5(second[2] as u8)];
+        return decode_etc1_subblocks(data, c1, c2, pixels);
+    };
+
+    // T and H mode pick one of four colors with a two bit index, high bit first
+    let index_bits = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    for y in 0..4 {
+        for x in 0..4 {
+            let p = etc_pixel(x, y);
+            let index = (index_bits >> (16 + p) & 1) << 1 | (index_bits >> p & 1);
+            let color = paint[index as usize];
+            pixels[y * 4 + x] = [color[0], color[1], color[2], 255];
+        }
+    }
+}
+
+// the original ETC1 modes: two halves with a base color each
+fn decode_etc1_subblocks(data: &[u8], c1: [i32; 3], c2: [i32; 3], pixels: &mut Block) {
+    let b3 = data[3];
+    let tables = [ETC_MODIFIERS[(b3 >> 5) as usize], ETC_MODIFIERS[(b3 >> 2 & 7) as usize]];
+    let flipped = b3 & 1 != 0;
+    let index_bits = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    for y in 0..4 {
+        for x in 0..4 {
+            let p = etc_pixel(x, y);
+            let second = if flipped { y >= 2 } else { x >= 2 };
+            let (base, table) = if second { (c2, tables[1]) } else { (c1, tables[0]) };
+            let modifier = table[(index_bits >> p & 1) as usize];
+            let modifier = if index_bits >> (16 + p) & 1 != 0 { -modifier } else { modifier };
+            let color = offset_color(base, modifier);
+            pixels[y * 4 + x] = [color[0], color[1], color[2], 255];
+        }
+    }
+}
+
+// a gradient from three colors at the corners
+fn decode_etc2_planar(data: &[u8], pixels: &mut Block) {
+    let b = data;
+    let origin = [
+        extend_6(b[0] >> 1 & 0x3F),
+        extend_7((b[0] & 1) << 6 | b[1] >> 1 & 0x3F),
+        extend_6((b[1] & 1) << 5 | (b[2] >> 3 & 3) << 3 | (b[2] & 3) << 1 | b[3] >> 7),
+    ];
+    let horizontal = [
+        extend_6((b[3] >> 2 & 0x1F) << 1 | b[3] & 1),
+        extend_7(b[4] >> 1),
+        extend_6((b[4] & 1) << 5 | b[5] >> 3),
+    ];
+    let vertical = [
+        extend_6((b[5] & 7) << 3 | b[6] >> 5),
+        extend_7((b[6] & 0x1F) << 2 | b[7] >> 6),
+        extend_6(b[7] & 0x3F),
+    ];
+    for y in 0..4 {
+        for x in 0..4 {
+            let channel = |c: usize| clamp_u8((x as i32 * (horizontal[c] - origin[c]) + y as i32 * (vertical[c] - origin[c]) + 4 * origin[c] + 2) >> 2);
+            pixels[y * 4 + x] = [channel(0), channel(1), channel(2), 255];
+        }
+    }
+}
+
+// an EAC block, written into `channel`. the 11 bit variant is for R11/RG11
+fn decode_eac(data: &[u8], channel: usize, eleven_bit: bool, pixels: &mut Block) {
+    let base = data[0] as i32;
+    let multiplier = (data[1] >> 4) as i32;
+    let table = EAC_MODIFIERS[(data[1] & 0xF) as usize];
+    let indices = data[2..8].iter().fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+    for y in 0..4 {
+        for x in 0..4 {
+            // the first pixel's index is in the highest bits
+            let index = (indices >> (45 - etc_pixel(x, y) * 3) & 7) as usize;
+            let modifier = table[index];
+            pixels[y * 4 + x][channel] = if eleven_bit {
+                let value = if multiplier == 0 {
+                    base * 8 + 4 + modifier
+                } else {
+                    base * 8 + 4 + modifier * multiplier * 8
+                };
+                ((value.clamp(0, 2047) * 255 + 1023) / 2047) as u8
+            } else {
+                clamp_u8(base + modifier * multiplier)
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bc1_solid_color() {
+        // red in both endpoints, every index 0
+        let block = [0x00, 0xF8, 0x00, 0xF8, 0, 0, 0, 0];
+        assert!(decode_block(BlockFormat::Bc1, &block).iter().all(|&p| p == [255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn bc1_three_color_mode_is_transparent() {
+        // c0 <= c1, every index 3
+        let block = [0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(decode_block(BlockFormat::Bc1, &block).iter().all(|&p| p == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn bc4_interpolates_and_has_extremes() {
+        // r0 <= r1: indices 6 and 7 are 0 and 255. pixel 0 gets index 6, pixel 1 index 7, pixel 2 index 1
+        let indices: u64 = 6 | 7 << 3 | 1 << 6;
+        let mut block = [10, 200, 0, 0, 0, 0, 0, 0];
+        block[2..8].copy_from_slice(&indices.to_le_bytes()[..6]);
+        let pixels = decode_block(BlockFormat::Bc4, &block);
+        assert_eq!(pixels[0], [0, 0, 0, 255]);
+        assert_eq!(pixels[1], [255, 0, 0, 255]);
+        assert_eq!(pixels[2], [200, 0, 0, 255]);
+        assert_eq!(pixels[3], [10, 0, 0, 255]);
+    }
+
+    #[test]
+    fn bc7_mode_6_solid_color() {
+        // mode 6 with every endpoint channel at 0x7F and p-bits 1, so everything is 255
+        let mut bits: u128 = 1 << 6;
+        let mut offset = 7;
+        for _ in 0..8 {
+            bits |= 0x7F << offset;
+            offset += 7;
+        }
+        bits |= 0b11 << offset;
+        let pixels = decode_block(BlockFormat::Bc7, &bits.to_le_bytes());
+        assert!(pixels.iter().all(|&p| p == [255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn bc7_reserved_mode_is_transparent_black() {
+        assert!(decode_block(BlockFormat::Bc7, &[0; 16]).iter().all(|&p| p == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn bc7_anchors_are_in_their_subsets() {
+        for partition in 0..64 {
+            assert_eq!(bc7_subset(2, partition, BC7_ANCHORS_2[partition] as usize), 1, "partition {}", partition);
+            assert_eq!(bc7_subset(3, partition, BC7_ANCHORS_3_SECOND[partition] as usize), 1, "partition {}", partition);
+            assert_eq!(bc7_subset(3, partition, BC7_ANCHORS_3_THIRD[partition] as usize), 2, "partition {}", partition);
+            assert_eq!(bc7_subset(2, partition, 0), 0);
+            assert_eq!(bc7_subset(3, partition, 0), 0);
+        }
+    }
+
+    #[test]
+    fn etc2_individual_mode_splits_the_block() {
+        // left half 0xF0.., right half 0x00.., smallest positive modifier everywhere
+        let block = [0xF0, 0xF0, 0xF0, 0x00, 0, 0, 0, 0];
+        let pixels = decode_block(BlockFormat::Etc2Rgb8, &block);
+        assert_eq!(pixels[0], [255, 255, 255, 255]);
+        assert_eq!(pixels[3], [2, 2, 2, 255]);
+        // flipped, the halves are the top and bottom
+        let block = [0xF0, 0xF0, 0xF0, 0x01, 0, 0, 0, 0];
+        let pixels = decode_block(BlockFormat::Etc2Rgb8, &block);
+        assert_eq!(pixels[3], [255, 255, 255, 255]);
+        assert_eq!(pixels[12], [2, 2, 2, 255]);
+    }
+
+    #[test]
+    fn etc2_differential_mode_adds_the_difference() {
+        // base 16 with a difference of +1 in every channel
+        let byte = 16 << 3 | 1;
+        let block = [byte, byte, byte, 0x02, 0, 0, 0, 0];
+        let pixels = decode_block(BlockFormat::Etc2Rgb8, &block);
+        let first = extend_5(16) as u8 + 2;
+        let second = extend_5(17) as u8 + 2;
+        assert_eq!(pixels[0], [first, first, first, 255]);
+        assert_eq!(pixels[2], [second, second, second, 255]);
+    }
+
+    #[test]
+    fn eac_alpha_uses_base_and_modifier() {
+        // base 100, multiplier 1, table 0, every index 4 (+2)
+        let indices: u64 = (0..16).fold(0, |bits, _| bits << 3 | 4);
+        let mut block = [0u8; 16];
+        block[0] = 100;
+        block[1] = 0x10;
+        block[2..8].copy_from_slice(&indices.to_be_bytes()[2..]);
+        let pixels = decode_block(BlockFormat::Etc2Rgba8, &block);
+        assert!(pixels.iter().all(|p| p[3] == 102));
+    }
+}
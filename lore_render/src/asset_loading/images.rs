@@ -1,15 +1,45 @@
-use image::{open, DynamicImage, ImageBuffer, Rgba};
+/*!
+ * loading images from files and from glTF models. the format is detected from
+ * the file's contents, so misnamed files still load. PNG (including 16-bit),
+ * JPEG, BMP, TGA and HDR are supported.
+ *
+ * textures are 8 bits per channel. 16-bit images load at full precision, but
+ * `create_texture` converts every image to 8-bit RGBA when it's uploaded. HDR
+ * files don't even get that far: the decoder already clamps them to 8-bit RGB
+ * (values above 1.0 are lost), so they're no use for lighting data.
+ */
 
-pub fn load(path: &str) -> DynamicImage {
-    if path.ends_with(".png") {
-        load_png(path)
-    } else {
-        panic!("Filetype not supported");
+use crate::error::{Error, Result};
+use image::{io::Reader, DynamicImage, ImageBuffer, ImageFormat, Rgba};
+use std::io::Cursor;
+
+/// Loads an image file, detecting its format from its contents. TGA files have
+/// no signature to detect, so they're recognized by their extension instead.
+/// HDR files come out clamped to 8 bits, and textures made from 16-bit images
+/// are 8-bit too, see the module docs.
+pub fn load(path: &str) -> Result<DynamicImage> {
+    load_from_file_contents(&std::fs::read(path)?, path)
+}
+
+/// Like `load`, for a file that has already been read. The path is only used to recognize TGA files.
+pub fn load_from_file_contents(bytes: &[u8], path: &str) -> Result<DynamicImage> {
+    let mut reader = Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+    if reader.format().is_none() {
+        let format = ImageFormat::from_path(path)
+            .map_err(|_| Error::UnsupportedFormat(format!("Filetype not supported: {}", path)))?;
+        reader.set_format(format);
     }
+    Ok(reader.decode()?)
+}
+
+/// Like `load`, for an image that's already in memory
+pub fn load_from_memory(bytes: &[u8]) -> Result<DynamicImage> {
+    Ok(image::load_from_memory(bytes)?)
 }
 
-pub fn load_png(path: &str) -> DynamicImage {
-    open(path).expect("Error loading image")
+/// Same as `load`, which isn't limited to PNGs anymore
+pub fn load_png(path: &str) -> Result<DynamicImage> {
+    load(path)
 }
 
 pub fn white_texture() -> DynamicImage {
@@ -18,11 +48,19 @@ pub fn white_texture() -> DynamicImage {
     DynamicImage::ImageRgba8(img_buf)
 }
 
+/// A normal map pointing straight out of the surface. Meant to be loaded as
+/// a linear texture.
+pub fn flat_normal_texture() -> DynamicImage {
+    let flat = Rgba([128, 128, 255, 255]);
+    let img_buf = ImageBuffer::from_pixel(1, 1, flat);
+    DynamicImage::ImageRgba8(img_buf)
+}
+
 pub fn default_texture() -> DynamicImage {
     let black = Rgba([0, 0, 0, 255]);
     let magenta = Rgba([255, 0, 255, 255]);
     let mut img_buf = ImageBuffer::from_pixel(8, 8, black);
-    for x in (0..8) {
+    for x in 0..8 {
         for y in (0..7).step_by(2) {
             img_buf.put_pixel(x, y + (if x % 2 == 0 { 0 } else { 1 }), magenta);
         }
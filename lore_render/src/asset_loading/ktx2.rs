@@ -0,0 +1,407 @@
+/*!
+ * reading KTX2 containers of block-compressed textures, which stay compressed
+ * on the GPU. the data has to be in a BC or ETC2/EAC format already, and GPUs
+ * that support neither get the texture decompressed into RGBA on the CPU.
+ *
+ * only 2D textures are supported (no arrays, cubemaps or 3D textures), and of
+ * the supercompression schemes only zstandard. Basis Universal data (UASTC, or
+ * ETC1S with BasisLZ) would need basis' transcoder, so those files have to be
+ * transcoded into BC7 or ETC2 ahead of time, with `ktx transcode` for example.
+ * that rules out the images glTF's KHR_texture_basisu is meant for, since it
+ * only allows basis data: `meshes::load_gltf` uses the texture's regular source
+ * for those instead.
+ *
+ * the container is described at https://registry.khronos.org/KTX/specs/2.0/ktxspec.v2.html
+ */
+
+use crate::{
+    asset_loading::block_decoding,
+    error::{Error, Result},
+};
+use image::RgbaImage;
+use std::io::Read;
+
+const IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+// identifier, then 9 u32 fields, then the offsets of the other sections
+const HEADER_LENGTH: usize = 80;
+const LEVEL_INDEX_ENTRY_LENGTH: usize = 24;
+
+// supercompression schemes
+const SUPERCOMPRESSION_NONE: u32 = 0;
+const SUPERCOMPRESSION_BASIS_LZ: u32 = 1;
+const SUPERCOMPRESSION_ZSTANDARD: u32 = 2;
+
+// data format descriptor values
+const COLOR_MODEL_ETC1S: u8 = 163;
+const COLOR_MODEL_UASTC: u8 = 166;
+
+// every format here uses 4x4 blocks
+const BLOCK_SIZE: u32 = 4;
+
+/// A GPU block compression format.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BlockFormat {
+    /// RGB with 1 bit alpha, 8 bytes per block
+    Bc1,
+    /// RGBA, 16 bytes per block
+    Bc3,
+    /// Red only, 8 bytes per block
+    Bc4,
+    /// Red and green, 16 bytes per block
+    Bc5,
+    /// High quality RGBA, 16 bytes per block
+    Bc7,
+    /// RGB, 8 bytes per block
+    Etc2Rgb8,
+    /// RGBA, 16 bytes per block
+    Etc2Rgba8,
+    /// Red only, 8 bytes per block
+    EacR11,
+    /// Red and green, 16 bytes per block
+    EacRg11,
+}
+
+impl BlockFormat {
+    pub fn bytes_per_block(&self) -> u32 {
+        match self {
+            BlockFormat::Bc1 | BlockFormat::Bc4 | BlockFormat::Etc2Rgb8 | BlockFormat::EacR11 => 8,
+            _ => 16,
+        }
+    }
+
+    // the format of a vulkan format number, and whether it's sRGB
+    fn from_vk_format(vk_format: u32) -> Option<(Self, bool)> {
+        Some(match vk_format {
+            131 | 133 => (BlockFormat::Bc1, false),
+            132 | 134 => (BlockFormat::Bc1, true),
+            137 => (BlockFormat::Bc3, false),
+            138 => (BlockFormat::Bc3, true),
+            139 => (BlockFormat::Bc4, false),
+            141 => (BlockFormat::Bc5, false),
+            145 => (BlockFormat::Bc7, false),
+            146 => (BlockFormat::Bc7, true),
+            147 => (BlockFormat::Etc2Rgb8, false),
+            148 => (BlockFormat::Etc2Rgb8, true),
+            151 => (BlockFormat::Etc2Rgba8, false),
+            152 => (BlockFormat::Etc2Rgba8, true),
+            153 => (BlockFormat::EacR11, false),
+            155 => (BlockFormat::EacRg11, false),
+            _ => return None,
+        })
+    }
+}
+
+/// A texture loaded from a KTX2 file, which `RenderingInstance::create_compressed_texture`
+/// uploads without decompressing it (if the GPU allows).
+#[derive(Clone, Debug)]
+pub struct CompressedImage {
+    pub width: u32,
+    pub height: u32,
+    /// Whether the file says it holds sRGB color. Pass this on in `TextureOptions::srgb`
+    /// unless you know better.
+    pub srgb: bool,
+    pub format: BlockFormat,
+    /// Mip levels, largest first. Always at least one.
+    pub levels: Vec<Vec<u8>>,
+}
+
+/// Whether the bytes are a KTX2 file
+pub fn is_ktx2(bytes: &[u8]) -> bool {
+    bytes.starts_with(&IDENTIFIER)
+}
+
+pub fn load_ktx2(path: &str) -> Result<CompressedImage> {
+    parse_ktx2(&std::fs::read(path)?)
+}
+
+pub fn parse_ktx2(bytes: &[u8]) -> Result<CompressedImage> {
+    if !is_ktx2(bytes) {
+        return Err(Error::UnsupportedFormat("Not a KTX2 file".into()));
+    }
+    if bytes.len() < HEADER_LENGTH {
+        return Err(Error::Decode("KTX2 header is cut off".into()));
+    }
+    let vk_format = read_u32(bytes, 12)?;
+    let width = read_u32(bytes, 20)?;
+    let height = read_u32(bytes, 24)?;
+    let depth = read_u32(bytes, 28)?;
+    let layer_count = read_u32(bytes, 32)?;
+    let face_count = read_u32(bytes, 36)?;
+    let level_count = read_u32(bytes, 40)?.max(1);
+    let supercompression = read_u32(bytes, 44)?;
+    let dfd_offset = read_u32(bytes, 48)? as usize;
+
+    if width == 0 || height == 0 {
+        return Err(Error::UnsupportedFormat("Textures can't be empty".into()));
+    }
+    if depth > 1 || layer_count > 1 || face_count != 1 {
+        return Err(Error::UnsupportedFormat("Only 2D KTX2 textures are supported".into()));
+    }
+    match supercompression {
+        SUPERCOMPRESSION_NONE | SUPERCOMPRESSION_ZSTANDARD => {}
+        SUPERCOMPRESSION_BASIS_LZ => return Err(Error::UnsupportedFormat("Basis Universal ETC1S, transcode it to BC7 or ETC2 first".into())),
+        other => return Err(Error::UnsupportedFormat(format!("KTX2 supercompression scheme {}", other))),
+    }
+
+    let (format, srgb) = match BlockFormat::from_vk_format(vk_format) {
+        Some(format) => format,
+        // basis data has no vkFormat, only the color model in the data format descriptor says what it is
+        None if vk_format == 0 => {
+            // the descriptor's basic block starts after its total size
+            let color_model = read_u8(bytes, dfd_offset + 4 + 8)?;
+            let name = match color_model {
+                COLOR_MODEL_UASTC => "Basis Universal UASTC",
+                COLOR_MODEL_ETC1S => "Basis Universal ETC1S",
+                _ => "KTX2 textures without a vkFormat",
+            };
+            return Err(Error::UnsupportedFormat(format!("{}, transcode it to BC7 or ETC2 first", name)));
+        }
+        None => return Err(Error::UnsupportedFormat(format!("KTX2 vkFormat {}", vk_format))),
+    };
+
+    // a level for every halving down to 1x1 at most, and the file has to have room
+    // for their index entries. both are checked before anything is allocated for them
+    let max_levels = u32::BITS - width.max(height).leading_zeros();
+    if level_count > max_levels {
+        return Err(Error::Decode(format!("KTX2 file claims {} mip levels, a {}x{} texture has at most {}", level_count, width, height, max_levels)));
+    }
+    if HEADER_LENGTH + level_count as usize * LEVEL_INDEX_ENTRY_LENGTH > bytes.len() {
+        return Err(Error::Decode("KTX2 level index is cut off".into()));
+    }
+
+    let mut image = CompressedImage {
+        width,
+        height,
+        srgb,
+        format,
+        levels: Vec::with_capacity(level_count as usize),
+    };
+    for level in 0..level_count as usize {
+        let entry = HEADER_LENGTH + level * LEVEL_INDEX_ENTRY_LENGTH;
+        let offset = read_u64(bytes, entry)? as usize;
+        let length = read_u64(bytes, entry + 8)? as usize;
+        let uncompressed_length = read_u64(bytes, entry + 16)?;
+        let expected_length = image.level_length(level);
+        let level_data = offset.checked_add(length)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or_else(|| Error::Decode(format!("KTX2 mip level {} is cut off", level)))?;
+        let level_data = if supercompression == SUPERCOMPRESSION_ZSTANDARD {
+            if uncompressed_length < expected_length as u64 {
+                return Err(Error::Decode(format!("KTX2 mip level {} is smaller than its dimensions", level)));
+            }
+            let mut source = level_data;
+            let decoder = ruzstd::StreamingDecoder::new(&mut source)
+                .map_err(|e| Error::Decode(format!("zstd: {}", e)))?;
+            // never more than the level needs, however far the data would decompress
+            let mut decompressed = Vec::new();
+            decoder.take(expected_length as u64).read_to_end(&mut decompressed)?;
+            decompressed
+        } else {
+            level_data.to_vec()
+        };
+        if level_data.len() < expected_length {
+            return Err(Error::Decode(format!("KTX2 mip level {} is smaller than its dimensions", level)));
+        }
+        image.levels.push(level_data);
+    }
+    Ok(image)
+}
+
+impl CompressedImage {
+    /// Width and height of a mip level, in pixels
+    pub fn level_size(&self, level: usize) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    /// Width and height of a mip level, in blocks
+    pub fn level_blocks(&self, level: usize) -> (u32, u32) {
+        let (width, height) = self.level_size(level);
+        (width.div_ceil(BLOCK_SIZE), height.div_ceil(BLOCK_SIZE))
+    }
+
+    /// How many bytes a mip level takes
+    pub fn level_length(&self, level: usize) -> usize {
+        let (blocks_x, blocks_y) = self.level_blocks(level);
+        blocks_x as usize * blocks_y as usize * self.format.bytes_per_block() as usize
+    }
+
+    /// The top mip level, decompressed on the CPU
+    pub fn to_rgba8(&self) -> RgbaImage {
+        let (width, height) = self.level_size(0);
+        let (blocks_x, _) = self.level_blocks(0);
+        let bytes_per_block = self.format.bytes_per_block() as usize;
+        let mut image = RgbaImage::new(width, height);
+        for (i, block) in self.levels[0].chunks_exact(bytes_per_block).take(self.level_length(0) / bytes_per_block).enumerate() {
+            let (block_x, block_y) = (i as u32 % blocks_x * BLOCK_SIZE, i as u32 / blocks_x * BLOCK_SIZE);
+            let pixels = block_decoding::decode_block(self.format, block);
+            // blocks hang over the edges of images that aren't a multiple of their size
+            for (j, pixel) in pixels.iter().enumerate() {
+                let (x, y) = (block_x + j as u32 % BLOCK_SIZE, block_y + j as u32 / BLOCK_SIZE);
+                if x < width && y < height {
+                    image.put_pixel(x, y, image::Rgba(*pixel));
+                }
+            }
+        }
+        image
+    }
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> Result<u8> {
+    bytes.get(offset).copied()
+        .ok_or_else(|| Error::Decode("KTX2 file is cut off".into()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    bytes.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::Decode("KTX2 file is cut off".into()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+    Ok(read_u32(bytes, offset)? as u64 | (read_u32(bytes, offset + 4)? as u64) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BC1_RED: [u8; 8] = [0x00, 0xF8, 0x00, 0xF8, 0, 0, 0, 0];
+
+    // a 2D KTX2 file with the given levels, and a data format descriptor with just a color model
+    fn ktx2_file(vk_format: u32, width: u32, height: u32, supercompression: u32, color_model: u8, levels: &[Vec<u8>]) -> Vec<u8> {
+        let index_end = HEADER_LENGTH + levels.len() * LEVEL_INDEX_ENTRY_LENGTH;
+        let dfd_length = 28;
+        let mut header = IDENTIFIER.to_vec();
+        for field in [vk_format, 1, width, height, 0, 0, 1, levels.len() as u32, supercompression, index_end as u32, dfd_length, 0, 0] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        header.extend_from_slice(&[0; 16]);
+
+        let mut offset = index_end + dfd_length as usize;
+        let mut index = Vec::new();
+        for level in levels {
+            for field in [offset as u64, level.len() as u64, level.len() as u64] {
+                index.extend_from_slice(&field.to_le_bytes());
+            }
+            offset += level.len();
+        }
+        let mut dfd = vec![0; dfd_length as usize];
+        dfd[0..4].copy_from_slice(&dfd_length.to_le_bytes());
+        dfd[4 + 8] = color_model;
+
+        let mut file = [header, index, dfd].concat();
+        for level in levels {
+            file.extend_from_slice(level);
+        }
+        file
+    }
+
+    // zstd frame holding the data as a single raw (uncompressed) block
+    fn zstd_raw_frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x28, 0xB5, 0x2F, 0xFD, 0x20, data.len() as u8];
+        let block_header = 1 | (data.len() as u32) << 3;
+        frame.extend_from_slice(&block_header.to_le_bytes()[..3]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn parses_block_compressed_levels() {
+        let file = ktx2_file(132, 8, 4, SUPERCOMPRESSION_NONE, 0, &[[BC1_RED; 2].concat(), BC1_RED.to_vec()]);
+        let image = parse_ktx2(&file).unwrap();
+        assert_eq!((image.width, image.height, image.srgb), (8, 4, true));
+        assert_eq!(image.format, BlockFormat::Bc1);
+        assert_eq!(image.levels, vec![[BC1_RED; 2].concat(), BC1_RED.to_vec()]);
+    }
+
+    #[test]
+    fn truncated_header_is_an_error() {
+        let file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_NONE, 0, &[BC1_RED.to_vec()]);
+        assert!(matches!(parse_ktx2(&file[..40]), Err(Error::Decode(_))));
+        assert!(matches!(parse_ktx2(&file[..8]), Err(Error::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn bad_level_offsets_are_an_error() {
+        let mut file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_NONE, 0, &[BC1_RED.to_vec()]);
+        // past the end of the file
+        file[HEADER_LENGTH..HEADER_LENGTH + 8].copy_from_slice(&1000u64.to_le_bytes());
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(_))));
+        // so far out that offset and length overflow
+        file[HEADER_LENGTH..HEADER_LENGTH + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn levels_smaller_than_their_size_are_an_error() {
+        let file = ktx2_file(131, 8, 8, SUPERCOMPRESSION_NONE, 0, &[BC1_RED.to_vec()]);
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn zstd_levels_are_decompressed() {
+        let file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_ZSTANDARD, 0, &[zstd_raw_frame(&BC1_RED)]);
+        assert_eq!(parse_ktx2(&file).unwrap().levels, vec![BC1_RED.to_vec()]);
+
+        let file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_ZSTANDARD, 0, &[BC1_RED.to_vec()]);
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn level_counts_are_checked_before_allocating() {
+        let mut file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_NONE, 0, &[BC1_RED.to_vec()]);
+        file[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(msg)) if msg.contains("mip levels")));
+        // 4x4 has room for 3 levels, but the index only has 1
+        file[40..44].copy_from_slice(&3u32.to_le_bytes());
+        file.truncate(HEADER_LENGTH + 2 * LEVEL_INDEX_ENTRY_LENGTH);
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(msg)) if msg.contains("level index")));
+    }
+
+    #[test]
+    fn zstd_output_stops_at_the_level_length() {
+        let file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_ZSTANDARD, 0, &[zstd_raw_frame(&[BC1_RED; 4].concat())]);
+        assert_eq!(parse_ktx2(&file).unwrap().levels, vec![BC1_RED.to_vec()]);
+
+        // less than the level needs, according to the index
+        let mut file = ktx2_file(131, 4, 4, SUPERCOMPRESSION_ZSTANDARD, 0, &[zstd_raw_frame(&BC1_RED)]);
+        file[HEADER_LENGTH + 16..HEADER_LENGTH + 24].copy_from_slice(&4u64.to_le_bytes());
+        assert!(matches!(parse_ktx2(&file), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn basis_data_is_told_apart_by_its_color_model() {
+        let uastc = ktx2_file(0, 4, 4, SUPERCOMPRESSION_NONE, COLOR_MODEL_UASTC, &[vec![0; 16]]);
+        assert!(matches!(parse_ktx2(&uastc), Err(Error::UnsupportedFormat(msg)) if msg.contains("UASTC")));
+        let etc1s = ktx2_file(0, 4, 4, SUPERCOMPRESSION_NONE, COLOR_MODEL_ETC1S, &[vec![0; 16]]);
+        assert!(matches!(parse_ktx2(&etc1s), Err(Error::UnsupportedFormat(msg)) if msg.contains("ETC1S")));
+        let other = ktx2_file(0, 4, 4, SUPERCOMPRESSION_NONE, 1, &[vec![0; 16]]);
+        assert!(matches!(parse_ktx2(&other), Err(Error::UnsupportedFormat(msg)) if msg.contains("vkFormat")));
+
+        // a descriptor outside of the file
+        let mut cut_off = uastc;
+        cut_off[48..52].copy_from_slice(&10_000u32.to_le_bytes());
+        assert!(matches!(parse_ktx2(&cut_off), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn level_blocks_round_up() {
+        let file = ktx2_file(131, 5, 3, SUPERCOMPRESSION_NONE, 0, &[[BC1_RED; 2].concat(), BC1_RED.to_vec(), BC1_RED.to_vec()]);
+        let image = parse_ktx2(&file).unwrap();
+        assert_eq!(image.level_size(0), (5, 3));
+        assert_eq!(image.level_blocks(0), (2, 1));
+        assert_eq!(image.level_size(1), (2, 1));
+        assert_eq!(image.level_blocks(1), (1, 1));
+        assert_eq!(image.level_size(2), (1, 1));
+        assert_eq!(image.level_blocks(2), (1, 1));
+        assert_eq!(image.level_length(0), 16);
+    }
+
+    #[test]
+    fn decompressing_crops_to_the_image() {
+        let file = ktx2_file(131, 5, 3, SUPERCOMPRESSION_NONE, 0, &[[BC1_RED; 2].concat()]);
+        let rgba = parse_ktx2(&file).unwrap().to_rgba8();
+        assert_eq!(rgba.dimensions(), (5, 3));
+        assert!(rgba.pixels().all(|p| p.0 == [255, 0, 0, 255]));
+    }
+}
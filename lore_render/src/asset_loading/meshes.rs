@@ -1,31 +1,201 @@
-use crate::mesh::{
-    Mesh, Vertex,
+#[cfg(feature = "ktx2")]
+use crate::asset_loading::ktx2::{self, CompressedImage};
+use crate::{
+    asset_loading::images,
+    error::{Error, Result},
+    mesh::{
+        Mesh, Vertex,
+    },
 };
+use cgmath::{Matrix4, SquareMatrix};
 use gltf;
+use std::path::{Path, PathBuf};
+
+/// Everything loaded from a glTF file.
+pub struct GltfModel {
+    /// Meshes in the same order as in the file
+    pub meshes: Vec<GltfMesh>,
+    /// Images in the same order as in the file
+    pub images: Vec<GltfImage>,
+    /// Materials in the same order as in the file. If any primitive has no
+    /// material, glTF's default material is added at the end for it.
+    pub materials: Vec<GltfMaterial>,
+    /// Objects, one for every node in the scene graph that has a mesh
+    pub objects: Vec<GltfObject>,
+}
+
+/// An image of a glTF model. KTX2 images (from `KHR_texture_basisu`, when they hold BC or
+/// ETC2/EAC blocks) stay compressed.
+#[derive(Clone, Debug)]
+pub enum GltfImage {
+    Decoded(image::DynamicImage),
+    #[cfg(feature = "ktx2")]
+    Compressed(CompressedImage),
+}
+
+/// A glTF mesh. glTF splits a mesh into primitives, one per material, and all
+/// of them have to be drawn to draw the whole mesh.
+pub struct GltfMesh {
+    pub name: Option<String>,
+    pub primitives: Vec<GltfPrimitive>,
+}
+
+pub struct GltfPrimitive {
+    pub mesh: Mesh,
+    /// Index into the model's materials
+    pub material: usize,
+}
+
+/// A glTF metallic-roughness material. Textures are indices into the model's images.
+#[derive(Clone, Debug)]
+pub struct GltfMaterial {
+    pub name: Option<String>,
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: Option<usize>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub metallic_roughness_texture: Option<usize>,
+    pub normal_texture: Option<usize>,
+    pub normal_scale: f32,
+    pub occlusion_texture: Option<usize>,
+    pub occlusion_strength: f32,
+    pub emissive_factor: [f32; 3],
+    pub emissive_texture: Option<usize>,
+}
+
+impl Default for GltfMaterial {
+    /// The material glTF uses for primitives that don't specify one
+    fn default() -> Self {
+        Self {
+            name: None,
+            base_color_factor: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            emissive_factor: [0.0, 0.0, 0.0],
+            emissive_texture: None,
+        }
+    }
+}
+
+impl GltfMaterial {
+    fn from_gltf(material: gltf::Material, texture_images: &[usize]) -> Self {
+        // the material points at glTF textures, but what we hand back are the textures' images
+        let image_of = |texture: gltf::Texture| texture_images[texture.index()];
+        let pbr = material.pbr_metallic_roughness();
+        Self {
+            name: material.name().map(String::from),
+            base_color_factor: pbr.base_color_factor(),
+            base_color_texture: pbr.base_color_texture().map(|info| image_of(info.texture())),
+            metallic_factor: pbr.metallic_factor(),
+            roughness_factor: pbr.roughness_factor(),
+            metallic_roughness_texture: pbr.metallic_roughness_texture().map(|info| image_of(info.texture())),
+            normal_texture: material.normal_texture().map(|normal| image_of(normal.texture())),
+            normal_scale: material.normal_texture().map_or(1.0, |normal| normal.scale()),
+            occlusion_texture: material.occlusion_texture().map(|occlusion| image_of(occlusion.texture())),
+            occlusion_strength: material.occlusion_texture().map_or(1.0, |occlusion| occlusion.strength()),
+            emissive_factor: material.emissive_factor(),
+            emissive_texture: material.emissive_texture().map(|info| image_of(info.texture())),
+        }
+    }
+}
+
+/// An object placed in a glTF scene.
+#[derive(Clone)]
+pub struct GltfObject {
+    /// Name of the node the object came from, if the file gave it one
+    pub name: Option<String>,
+    /// World transform of the node, with all of its parents' transforms applied.
+    /// Only approximated if the transform has shear, see `transform` for the exact one.
+    pub instance: crate::ObjectInstance,
+    /// The exact world transform of the node
+    pub transform: Matrix4<f32>,
+    /// Index into the model's meshes
+    pub mesh: usize,
+}
 
 // https://github.com/KhronosGroup/glTF/blob/main/specification/2.0/figures/gltfOverview-2.0.0b.png
 // ^ infographic on the structure of a glTF file
 
-// returns:
-// Vec of meshes and the index (into returned textures list) of the texture that goes to that mesh
-// Vec of textures
-// Vec of object instances and the index (into returned meshes list) of the mesh that the instance is of
-pub fn load_gltf(path: &str) -> (Vec<(Mesh, Option<usize>)>, Vec<image::DynamicImage>, Vec<(crate::ObjectInstance, usize)>) {
-    let (document, buffers, gltf_images) = match gltf::import(path) {
-        Err(_) => {
-            println!("Failed to load path {}", path);
-            return (Vec::new(), Vec::new(), Vec::new());
-        },
-        Ok(imported) => imported,
+/// Loads a .gltf or .glb file. Textures with a `KHR_texture_basisu` source use its KTX2 image,
+/// unless it can't be read, in which case they fall back to their regular source with a warning.
+/// Basis Universal data (UASTC and ETC1S) can't be transcoded (see `ktx2`), so only KTX2 images
+/// that hold BC or ETC2/EAC blocks are used. The extension itself only allows Basis data, so
+/// files that follow it to the letter always fall back. The gltf crate insists on that regular
+/// source being there, so files without one can't be loaded.
+pub fn load_gltf(path: &str) -> Result<GltfModel> {
+    let bytes = std::fs::read(path)?;
+    let (json, mut blob) = if bytes.starts_with(b"glTF") {
+        let glb = gltf::Glb::from_slice(&bytes)?;
+        (glb.json.into_owned(), glb.bin.map(|bin| bin.into_owned()))
+    } else {
+        (bytes, None)
     };
+    // the gltf crate drops extensions it doesn't know, so they're read from the JSON before it gets it
+    let json: gltf::json::Value = gltf::json::deserialize::from_slice(&json).map_err(gltf::Error::Deserialize)?;
+    #[cfg(feature = "ktx2")]
+    let basisu_sources = basisu_sources(&json);
+    let document = gltf::Document::from_json(gltf::json::deserialize::from_value(json).map_err(gltf::Error::Deserialize)?)?;
+
+    let base = Path::new(path).parent();
+    let buffers = document.buffers()
+        .map(|buffer| {
+            let data = match buffer.source() {
+                gltf::buffer::Source::Bin => blob.take()
+                    .ok_or_else(|| Error::Decode("glTF buffer refers to a binary chunk the file doesn't have".into()))?,
+                gltf::buffer::Source::Uri(uri) => read_uri(uri, base)?,
+            };
+            if data.len() < buffer.length() {
+                return Err(Error::Decode(format!("glTF buffer {} is shorter than it says", buffer.index())));
+            }
+            Ok(data)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // decoding errors only matter for images that end up being used
+    let mut gltf_images = document.images()
+        .map(|image| load_gltf_image(image, base, &buffers))
+        .collect::<Vec<_>>();
+    let texture_images = document.textures()
+        .map(|texture| {
+            #[cfg(feature = "ktx2")]
+            if let Some(basisu) = basisu_sources.get(texture.index()).copied().flatten() {
+                match gltf_images.get(basisu) {
+                    Some(Ok(_)) => return basisu,
+                    Some(Err(e)) => log::warn!("glTF texture {} falls back to its regular source, its KHR_texture_basisu image can't be used: {}", texture.index(), e),
+                    None => log::warn!("glTF texture {} falls back to its regular source, its KHR_texture_basisu image doesn't exist", texture.index()),
+                }
+            }
+            texture.source().index()
+        })
+        .collect::<Vec<_>>();
+    // unreadable images no texture uses anymore keep their place with the placeholder
+    for (index, image) in gltf_images.iter_mut().enumerate() {
+        if image.is_err() && !texture_images.contains(&index) {
+            *image = Ok(GltfImage::Decoded(images::default_texture()));
+        }
+    }
+    let images = gltf_images.into_iter().collect::<Result<Vec<_>>>()?;
+
+    let mut materials = document.materials()
+        .map(|material| GltfMaterial::from_gltf(material, &texture_images))
+        .collect::<Vec<_>>();
+    // primitives without a material all share glTF's default one
+    let mut default_material = None;
 
     let mut meshes = Vec::new();
     for gltf_mesh in document.meshes() {
+        let mut primitives = Vec::new();
         for prim in gltf_mesh.primitives() {
             let mut vertices = Vec::<Vertex>::new();
             let mut indices = Vec::<u32>::new();
             
-            let reader = prim.reader(|buffer| Some(&buffers[buffer.index()]));
+            let reader = prim.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
             if let Some(iter) = reader.read_positions() {
                 for vert_pos in iter {
                     vertices.push(Vertex {
@@ -50,55 +220,131 @@ pub fn load_gltf(path: &str) -> (Vec<(Mesh, Option<usize>)>, Vec<image::DynamicI
                     indices.push(ind);
                 }
             }
-            let texture_mayb = prim.material().pbr_metallic_roughness().base_color_texture();
-            let texture_id = match texture_mayb {
-                Some(texture) => Some(texture.texture().index()),
-                None => None,
+            let material = match prim.material().index() {
+                Some(index) => index,
+                None => *default_material.get_or_insert_with(|| {
+                    materials.push(GltfMaterial::default());
+                    materials.len() - 1
+                }),
             };
 
-            meshes.push((Mesh::new(vertices, indices), texture_id));
+            primitives.push(GltfPrimitive {
+                mesh: Mesh::new(vertices, indices),
+                material,
+            });
         }
+        meshes.push(GltfMesh {
+            name: gltf_mesh.name().map(String::from),
+            primitives,
+        });
     }
 
-    let mut images: Vec<image::DynamicImage> = Vec::new();
-    for gltf_image in gltf_images {
-        if gltf_image.format != gltf::image::Format::R8G8B8A8 {
-            panic!("i don't like the image format. {:?}", gltf_image.format);
-        };
-        let img_mayb = image::ImageBuffer::from_raw(gltf_image.width, gltf_image.height, gltf_image.pixels);
-        if let Some(img) = img_mayb {
-            images.push(image::DynamicImage::ImageRgba8(img));
-        }
-    }
-
-    let mut instances = Vec::new();
+    let mut objects = Vec::new();
     for scene in document.scenes() {
         for node in scene.nodes() {
-            instances.append(&mut objects_from_node(node));
+            objects_from_node(node, Matrix4::identity(), &mut objects);
         }
     }
 
-    (meshes, images, instances)
+    Ok(GltfModel {
+        meshes,
+        images,
+        materials,
+        objects,
+    })
+}
+
+// walks the node and all of its children, composing transforms on the way down
+fn objects_from_node(node: gltf::Node, parent_transform: Matrix4<f32>, objects: &mut Vec<GltfObject>) {
+    let transform = parent_transform * Matrix4::from(node.transform().matrix());
+    if let Some(gltf_mesh) = node.mesh() {
+        // shear comes from rotating the children of non-uniformly scaled nodes
+        let instance = crate::ObjectInstance::try_from_matrix(transform).unwrap_or_else(|_| {
+            log::warn!("glTF node {} has a sheared transform, its instance only approximates it", node.index());
+            crate::ObjectInstance::from_matrix(transform)
+        });
+        objects.push(GltfObject {
+            name: node.name().map(String::from),
+            instance,
+            transform,
+            mesh: gltf_mesh.index(),
+        });
+    }
+    for child in node.children() {
+        objects_from_node(child, transform, objects);
+    }
 }
 
-fn objects_from_node(node: gltf::Node) -> Vec<(crate::ObjectInstance, usize)> {
-    let mut instances = Vec::new();
-    if node.children().count() != 0 {
-        for child in node.children() {
-            instances.append(&mut objects_from_node(child));
+fn load_gltf_image(image: gltf::Image, base: Option<&Path>, buffers: &[Vec<u8>]) -> Result<GltfImage> {
+    let bytes = match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let start = view.offset();
+            buffers[view.buffer().index()].get(start..start + view.length())
+                .ok_or_else(|| Error::Decode(format!("glTF image {} is outside of its buffer", image.index())))?
+                .to_vec()
         }
-    } else {
-        if let gltf::scene::Transform::Decomposed{ translation, rotation, scale, } = node.transform() {
-            if let Some(gltf_mesh) = node.mesh() {
-                let instance = crate::ObjectInstance {
-                    position: translation.into(),
-                    rotation: rotation.into(),
-                };
-
-                instances.push((instance, gltf_mesh.index()));
+        gltf::image::Source::Uri { uri, .. } => read_uri(uri, base)?,
+    };
+    #[cfg(feature = "ktx2")]
+    if ktx2::is_ktx2(&bytes) {
+        return Ok(GltfImage::Compressed(ktx2::parse_ktx2(&bytes)?));
+    }
+    Ok(GltfImage::Decoded(images::load_from_memory(&bytes)?))
+}
+
+// what a buffer or image URI points at. files are relative to the glTF file, and
+// like anything else in a URI their names can be percent-encoded
+fn read_uri(uri: &str, base: Option<&Path>) -> Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        return match data.split_once(";base64,") {
+            Some((_, encoded)) => base64::decode(encoded).map_err(|e| Error::Decode(e.to_string())),
+            // data without ";base64" is percent-encoded after the media type
+            None => {
+                let (_, encoded) = data.split_once(',')
+                    .ok_or_else(|| Error::Decode("glTF data URI has no data".into()))?;
+                Ok(percent_encoding::percent_decode_str(encoded).collect())
             }
-        }
+        };
     }
+    let path = percent_encoding::percent_decode_str(uri).decode_utf8()
+        .map_err(|e| Error::Decode(format!("glTF URI {} isn't a valid path: {}", uri, e)))?;
+    let path = match path.strip_prefix("file://").or_else(|| path.strip_prefix("file:")) {
+        Some(absolute) => PathBuf::from(absolute),
+        None => base.map_or_else(|| PathBuf::from(&*path), |base| base.join(&*path)),
+    };
+    Ok(std::fs::read(path)?)
+}
 
-    instances
-}
\ No newline at end of file
+// the KTX2 image of every texture that has one
+#[cfg(feature = "ktx2")]
+fn basisu_sources(json: &gltf::json::Value) -> Vec<Option<usize>> {
+    let textures = match json["textures"].as_array() {
+        Some(textures) => textures,
+        None => return Vec::new(),
+    };
+    textures.iter()
+        .map(|texture| texture["extensions"]["KHR_texture_basisu"]["source"].as_u64().map(|source| source as usize))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_uris_are_decoded() {
+        assert_eq!(read_uri("data:application/octet-stream;base64,AQID", None).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_uri("data:text/plain,a%20b", None).unwrap(), b"a b".to_vec());
+        assert!(read_uri("data:nothing", None).is_err());
+    }
+
+    #[test]
+    fn file_uris_are_percent_decoded() {
+        let dir = std::env::temp_dir().join("lore_render_uri_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("My Texture.bin"), [7, 8]).unwrap();
+        assert_eq!(read_uri("My%20Texture.bin", Some(&dir)).unwrap(), vec![7, 8]);
+        let absolute = format!("file://{}", dir.join("My%20Texture.bin").display());
+        assert_eq!(read_uri(&absolute, None).unwrap(), vec![7, 8]);
+    }
+}
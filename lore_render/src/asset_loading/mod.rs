@@ -1,2 +1,6 @@
 pub mod images;
-pub mod meshes;
\ No newline at end of file
+#[cfg(feature = "ktx2")]
+pub mod ktx2;
+#[cfg(feature = "ktx2")]
+mod block_decoding;
+pub mod meshes;
@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// Everything that can go wrong in lore_render without it being a bug in lore_render.
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read
+    Io(std::io::Error),
+    /// A file was read, but its contents are broken
+    Decode(String),
+    /// The data is in a format lore_render doesn't handle
+    UnsupportedFormat(String),
+    /// An id was passed that doesn't refer to a live resource. The str says what kind of resource.
+    InvalidHandle(&'static str),
+    /// A resource couldn't be deleted because something else still uses it. The str says what kind of resource.
+    ResourceInUse(&'static str),
+    /// Too many of some resource exist at once. The str says what kind of resource.
+    LimitReached(&'static str),
+    /// The GPU or the graphics backend refused to do something
+    Gpu(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Decode(msg) => write!(f, "Decoding error: {}", msg),
+            Error::UnsupportedFormat(msg) => write!(f, "Unsupported format: {}", msg),
+            Error::InvalidHandle(kind) => write!(f, "Invalid {} handle", kind),
+            Error::ResourceInUse(kind) => write!(f, "The {} is still in use", kind),
+            Error::LimitReached(kind) => write!(f, "Too many {}", kind),
+            Error::Gpu(msg) => write!(f, "GPU error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => Error::Io(e),
+            image::ImageError::Unsupported(e) => Error::UnsupportedFormat(e.to_string()),
+            e => Error::Decode(e.to_string()),
+        }
+    }
+}
+
+impl From<gltf::Error> for Error {
+    fn from(e: gltf::Error) -> Self {
+        match e {
+            gltf::Error::Io(e) => Error::Io(e),
+            gltf::Error::Image(e) => Error::from(e),
+            gltf::Error::UnsupportedImageEncoding | gltf::Error::UnsupportedScheme => Error::UnsupportedFormat(e.to_string()),
+            e => Error::Decode(e.to_string()),
+        }
+    }
+}
+
+impl From<wgpu::Error> for Error {
+    fn from(e: wgpu::Error) -> Self {
+        Error::Gpu(e.to_string())
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for Error {
+    fn from(e: wgpu::BufferAsyncError) -> Self {
+        Error::Gpu(e.to_string())
+    }
+}
@@ -0,0 +1,250 @@
+/*!
+ * typed, generational handles for engine resources.
+ *
+ * resources live in slabs, and a slab reuses the slot of a removed value for the
+ * next one inserted. a plain slab key for a removed resource would then silently
+ * point at whatever took its place. every slot here also has a generation that is
+ * bumped when the slot is freed, and a handle only resolves if its generation
+ * still matches, so a stale handle is reported as invalid instead.
+ *
+ * each kind of resource gets its own handle type, so passing a texture where a
+ * pipeline is expected doesn't compile.
+ */
+
+use crate::error::{Error, Result};
+use slab::Slab;
+use std::{
+    marker::PhantomData,
+    ops::{Index, IndexMut},
+};
+
+/// The untyped part of every handle: a slot and the generation it was handed out in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct RawHandle {
+    index: usize,
+    generation: u32,
+}
+
+pub(crate) trait Handle: Copy {
+    /// What the handle refers to, for error messages
+    const KIND: &'static str;
+
+    fn from_raw(raw: RawHandle) -> Self;
+    fn raw(self) -> RawHandle;
+}
+
+macro_rules! handle_type {
+    ($(#[$meta:meta])* $vis:vis $name:ident, $kind:expr) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        $vis struct $name(RawHandle);
+
+        impl Handle for $name {
+            const KIND: &'static str = $kind;
+
+            fn from_raw(raw: RawHandle) -> Self {
+                Self(raw)
+            }
+
+            fn raw(self) -> RawHandle {
+                self.0
+            }
+        }
+    };
+}
+
+handle_type!(
+    /// A mesh bound with `RenderingInstance::bind_mesh`.
+    pub MeshHandle, "mesh"
+);
+handle_type!(
+    /// A texture created with `RenderingInstance::create_texture`.
+    pub TextureHandle, "texture"
+);
+handle_type!(
+    /// A render pipeline created with `RenderingInstance::create_render_pipeline`.
+    pub PipelineHandle, "render pipeline"
+);
+handle_type!(
+    /// A font loaded with `RenderingInstance::create_glyph_brush`.
+    pub GlyphBrushHandle, "glyph brush"
+);
+handle_type!(
+    /// A text box created with `RenderingInstance::create_text_box`.
+    pub TextBoxHandle, "text box"
+);
+handle_type!(
+    /// A light added with `RenderingInstance::add_light`.
+    pub LightHandle, "light"
+);
+handle_type!(
+    /// A material created with `RenderingInstance::create_material`.
+    pub MaterialHandle, "material"
+);
+handle_type!(
+    /// A camera created with `RenderingInstance::create_camera`.
+    pub CameraHandle, "camera"
+);
+handle_type!(
+    /// A glTF model loaded in the background with `RenderingInstance::load_model`.
+    pub ModelHandle, "model"
+);
+handle_type!(
+    // an instance's slot within its mesh, see InstanceHandle
+    pub(crate) InstanceKey, "instance"
+);
+
+/// An object instance created with `RenderingInstance::create_object_instance`.
+/// Instances belong to a mesh, so the handle stops being valid when either the
+/// instance or its mesh is removed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceHandle {
+    pub(crate) mesh: MeshHandle,
+    pub(crate) key: InstanceKey,
+}
+
+impl InstanceHandle {
+    /// The mesh this is an instance of
+    pub fn mesh(&self) -> MeshHandle {
+        self.mesh
+    }
+}
+
+/// A `Slab` that hands out handles of type `H` instead of bare keys.
+pub(crate) struct HandleSlab<H: Handle, T> {
+    slab: Slab<T>,
+    // current generation of every slot the slab has ever used
+    generations: Vec<u32>,
+    _handle: PhantomData<H>,
+}
+
+impl<H: Handle, T> HandleSlab<H, T> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slab: Slab::with_capacity(capacity),
+            generations: Vec::with_capacity(capacity),
+            _handle: PhantomData,
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> H {
+        let index = self.slab.insert(value);
+        if index == self.generations.len() {
+            self.generations.push(0);
+        }
+        H::from_raw(RawHandle {
+            index,
+            generation: self.generations[index],
+        })
+    }
+
+    pub fn contains(&self, handle: H) -> bool {
+        let raw = handle.raw();
+        self.generations.get(raw.index) == Some(&raw.generation) && self.slab.contains(raw.index)
+    }
+
+    pub fn get(&self, handle: H) -> Option<&T> {
+        if self.contains(handle) {
+            self.slab.get(handle.raw().index)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: H) -> Option<&mut T> {
+        if self.contains(handle) {
+            self.slab.get_mut(handle.raw().index)
+        } else {
+            None
+        }
+    }
+
+    /// Like `get`, but a stale handle is an `Error::InvalidHandle`.
+    pub fn try_get(&self, handle: H) -> Result<&T> {
+        self.get(handle).ok_or(Error::InvalidHandle(H::KIND))
+    }
+
+    /// Like `get_mut`, but a stale handle is an `Error::InvalidHandle`.
+    pub fn try_get_mut(&mut self, handle: H) -> Result<&mut T> {
+        self.get_mut(handle).ok_or(Error::InvalidHandle(H::KIND))
+    }
+
+    pub fn remove(&mut self, handle: H) -> Result<T> {
+        if !self.contains(handle) {
+            return Err(Error::InvalidHandle(H::KIND));
+        }
+        let index = handle.raw().index;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        Ok(self.slab.remove(index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (H, &T)> {
+        let generations = &self.generations;
+        self.slab.iter().map(move |(index, value)| {
+            (H::from_raw(RawHandle { index, generation: generations[index] }), value)
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (H, &mut T)> {
+        let generations = &self.generations;
+        self.slab.iter_mut().map(move |(index, value)| {
+            (H::from_raw(RawHandle { index, generation: generations[index] }), value)
+        })
+    }
+}
+
+/// Panics on a stale handle. Only for handles that were already checked.
+impl<H: Handle, T> Index<H> for HandleSlab<H, T> {
+    type Output = T;
+
+    fn index(&self, handle: H) -> &T {
+        self.get(handle).unwrap_or_else(|| panic!("Invalid {} handle", H::KIND))
+    }
+}
+
+impl<H: Handle, T> IndexMut<H> for HandleSlab<H, T> {
+    fn index_mut(&mut self, handle: H) -> &mut T {
+        self.get_mut(handle).unwrap_or_else(|| panic!("Invalid {} handle", H::KIND))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_handles_are_invalid() {
+        let mut slab = HandleSlab::<MeshHandle, &str>::new();
+        let handle = slab.insert("mesh");
+        assert_eq!(slab.try_get(handle).ok(), Some(&"mesh"));
+
+        assert_eq!(slab.remove(handle).ok(), Some("mesh"));
+        assert!(!slab.contains(handle));
+        assert!(matches!(slab.try_get(handle), Err(Error::InvalidHandle("mesh"))));
+        assert!(matches!(slab.remove(handle), Err(Error::InvalidHandle("mesh"))));
+    }
+
+    #[test]
+    fn stale_handles_dont_reach_a_reused_slot() {
+        let mut slab = HandleSlab::<MeshHandle, &str>::new();
+        let old = slab.insert("old");
+        slab.remove(old).unwrap();
+        let new = slab.insert("new");
+
+        // same slot, newer generation
+        assert_eq!(new.raw().index, old.raw().index);
+        assert_ne!(new, old);
+        assert!(matches!(slab.try_get(old), Err(Error::InvalidHandle(_))));
+        assert!(slab.get_mut(old).is_none());
+        assert!(slab.remove(old).is_err());
+        assert_eq!(slab.try_get(new).ok(), Some(&"new"));
+    }
+}
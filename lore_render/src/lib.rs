@@ -1,18 +1,40 @@
 mod rendering;
 mod mesh;
+mod error;
+mod handle;
 pub mod asset_loading;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub use rendering::{
     engine::{
         RenderingInstance,
         ObjectInstance,
         TextInstance,
+        PipelineOptions,
+        CullingStats,
     },
+    model::BoundModel,
+    assets::{AssetId, LoadState, LoadProgress},
+    camera::{Projection, Viewport, ALL_LAYERS},
+    picking::PickHit,
+    lights::{Light, DirectionalLight, PointLight, SpotLight, MAX_LIGHTS},
+    shadows::MAX_SHADOW_MAPS,
+    material::Material,
+    textures::{TextureOptions, Filter, AddressMode},
     run,
     InputEvent,
 };
-pub use mesh::{Mesh, Vertex};
+pub use mesh::{Mesh, Vertex, Aabb, BoundingSphere};
+pub use error::{Error, Result};
+pub use handle::{
+    MeshHandle, TextureHandle, PipelineHandle, InstanceHandle,
+    GlyphBrushHandle, TextBoxHandle, LightHandle, MaterialHandle,
+    CameraHandle, ModelHandle,
+};
 pub use cgmath;
+pub use image;
+pub use wgpu;
 pub use winit::event::{
     KeyboardInput, VirtualKeyCode, ElementState,
     MouseButton,
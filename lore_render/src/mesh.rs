@@ -1,3 +1,4 @@
+use crate::error::{Error, Result};
 use cgmath::{Vector3, Matrix4, Point3};
 use cgmath::prelude::*;
 use bytemuck::{
@@ -12,6 +13,20 @@ pub struct Vertex {
     pub tex_coords: [f32; 2],
 }
 
+/// Axis-aligned bounding box, in the mesh's own coordinates
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+/// Sphere containing every vertex of a mesh, in the mesh's own coordinates
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
 #[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
@@ -26,6 +41,38 @@ impl Mesh {
         }
     }
 
+    /// Fails with `Error::Decode` if an index points past the last vertex
+    pub fn check_indices(&self) -> Result<()> {
+        match self.indices.iter().find(|&&index| index as usize >= self.vertices.len()) {
+            Some(index) => Err(Error::Decode(format!("Mesh index {} is out of range of its {} vertices", index, self.vertices.len()))),
+            None => Ok(()),
+        }
+    }
+
+    /// `None` if the mesh has no vertices
+    pub fn aabb(&self) -> Option<Aabb> {
+        let mut positions = self.vertices.iter().map(|v| Point3::from(v.position));
+        let first = positions.next()?;
+        Some(positions.fold(Aabb { min: first, max: first }, |aabb, p| Aabb {
+            min: Point3::new(aabb.min.x.min(p.x), aabb.min.y.min(p.y), aabb.min.z.min(p.z)),
+            max: Point3::new(aabb.max.x.max(p.x), aabb.max.y.max(p.y), aabb.max.z.max(p.z)),
+        }))
+    }
+
+    /// Centered on the AABB, which isn't the smallest possible sphere but is
+    /// close enough for culling. `None` if the mesh has no vertices.
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        let aabb = self.aabb()?;
+        let center = aabb.min.midpoint(aabb.max);
+        let radius = self.vertices.iter()
+            .map(|v| Point3::from(v.position).distance(center))
+            .fold(0.0, f32::max);
+        Some(BoundingSphere {
+            center,
+            radius,
+        })
+    }
+
     pub fn change_coord_system(&mut self, old_up: Vector3::<f32>, new_up: Vector3::<f32>) {
         let rot_mat = Matrix4::<f32>::look_at_rh(Point3::<f32>::new(0.0, 0.0, 0.0), Point3::<f32>::from_vec(new_up), old_up);
         for i in 0..self.vertices.len() {
@@ -35,3 +82,60 @@ impl Mesh {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(positions: &[[f32; 3]]) -> Mesh {
+        let vertices = positions.iter().map(|&position| Vertex {
+            position,
+            normal: [0.0, 0.0, 1.0],
+            tex_coords: [0.0, 0.0],
+        }).collect();
+        Mesh::new(vertices, Vec::new())
+    }
+
+    #[test]
+    fn empty_mesh_has_no_bounds() {
+        let empty = mesh(&[]);
+        assert_eq!(empty.aabb(), None);
+        assert_eq!(empty.bounding_sphere(), None);
+    }
+
+    #[test]
+    fn single_vertex_bounds_are_a_point() {
+        let point = mesh(&[[1.0, 2.0, 3.0]]);
+        assert_eq!(point.aabb(), Some(Aabb { min: Point3::new(1.0, 2.0, 3.0), max: Point3::new(1.0, 2.0, 3.0) }));
+        assert_eq!(point.bounding_sphere(), Some(BoundingSphere { center: Point3::new(1.0, 2.0, 3.0), radius: 0.0 }));
+    }
+
+    #[test]
+    fn indices_past_the_vertices_are_an_error() {
+        let mut triangle = mesh(&[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]);
+        triangle.indices = vec![0, 1, 2];
+        assert!(triangle.check_indices().is_ok());
+        triangle.indices = vec![0, 1, 3];
+        assert!(matches!(triangle.check_indices(), Err(Error::Decode(_))));
+        assert!(mesh(&[]).check_indices().is_ok());
+    }
+
+    #[test]
+    fn aabb_spans_every_axis_separately() {
+        let mesh = mesh(&[[-1.0, 5.0, 0.0], [2.0, -3.0, 1.0], [0.0, 0.0, -4.0]]);
+        assert_eq!(mesh.aabb(), Some(Aabb { min: Point3::new(-1.0, -3.0, -4.0), max: Point3::new(2.0, 5.0, 1.0) }));
+    }
+
+    #[test]
+    fn bounding_sphere_contains_every_vertex() {
+        let positions = [[-1.0, 5.0, 0.0], [2.0, -3.0, 1.0], [0.0, 0.0, -4.0], [0.5, 0.5, 0.5]];
+        let sphere = mesh(&positions).bounding_sphere().unwrap();
+        assert_eq!(sphere.center, Point3::new(0.5, 1.0, -1.5));
+        for position in positions {
+            assert!(Point3::from(position).distance(sphere.center) <= sphere.radius);
+        }
+        // and touches the farthest one
+        let farthest = positions.iter().map(|&p| Point3::from(p).distance(sphere.center)).fold(0.0, f32::max);
+        assert_eq!(sphere.radius, farthest);
+    }
+}
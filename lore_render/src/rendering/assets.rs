@@ -0,0 +1,348 @@
+/*!
+ * loading assets in the background. files are read and decoded on worker
+ * threads, and the engine picks up whatever is finished at every update and
+ * uploads it on the main thread, which is the only one with the GPU.
+ *
+ * textures get their handle right away, and show the magenta placeholder until
+ * the real image is in. models are bound once they're decoded, so until then
+ * there's nothing to place. either way `load_state` says how far along it is.
+ *
+ * `LoadProgress` counts a batch: everything started since the last time nothing
+ * was loading. so a loading screen goes from 0 to 1 once, and the counts don't
+ * grow forever in a game that keeps streaming assets in.
+ */
+
+#[cfg(feature = "ktx2")]
+use crate::asset_loading::ktx2::{self, CompressedImage};
+use crate::{
+    asset_loading::{
+        images,
+        meshes::{self, GltfModel, GltfObject},
+    },
+    error::{Error, Result},
+    handle::{HandleSlab, ModelHandle, PipelineHandle, TextureHandle},
+    rendering::{
+        model::BoundModel,
+        textures::TextureOptions,
+    },
+};
+use image::DynamicImage;
+use std::{
+    any::Any,
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+};
+
+/// The most worker threads assets are decoded on
+const MAX_WORKERS: usize = 4;
+
+/// Anything that can be loaded in the background.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AssetId {
+    Texture(TextureHandle),
+    Model(ModelHandle),
+}
+
+impl From<TextureHandle> for AssetId {
+    fn from(texture: TextureHandle) -> Self {
+        AssetId::Texture(texture)
+    }
+}
+
+impl From<ModelHandle> for AssetId {
+    fn from(model: ModelHandle) -> Self {
+        AssetId::Model(model)
+    }
+}
+
+/// How far along an asset is.
+#[derive(Debug)]
+pub enum LoadState {
+    /// Still being read or decoded
+    Loading,
+    /// Uploaded and ready to use
+    Loaded,
+    /// Reading, decoding or uploading it failed. Textures keep showing the placeholder.
+    Failed(Error),
+}
+
+/// How many of the assets in the current batch are done. A batch is everything
+/// started loading since the last time nothing was loading.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub loading: usize,
+    pub loaded: usize,
+    pub failed: usize,
+}
+
+impl LoadProgress {
+    pub fn total(&self) -> usize {
+        self.loading + self.loaded + self.failed
+    }
+
+    /// Fraction of assets that are done, whether they failed or not. 1 if nothing was loaded at all.
+    pub fn fraction(&self) -> f32 {
+        if self.total() == 0 {
+            1.0
+        } else {
+            (self.loaded + self.failed) as f32 / self.total() as f32
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.loading == 0
+    }
+}
+
+// what a worker is asked to load
+enum Job {
+    Texture {
+        texture: TextureHandle,
+        options: TextureOptions,
+        path: String,
+    },
+    Model {
+        model: ModelHandle,
+        path: String,
+    },
+}
+
+pub(in crate::rendering) enum TextureData {
+    Image(DynamicImage),
+    #[cfg(feature = "ktx2")]
+    Compressed(CompressedImage),
+}
+
+// what a worker hands back
+pub(in crate::rendering) enum Decoded {
+    Texture(TextureHandle, TextureOptions, Result<TextureData>),
+    Model(ModelHandle, Result<GltfModel>),
+    // the job panicked
+    Failed(AssetId, Error),
+}
+
+impl Job {
+    fn asset(&self) -> AssetId {
+        match self {
+            Job::Texture { texture, .. } => AssetId::Texture(*texture),
+            Job::Model { model, .. } => AssetId::Model(*model),
+        }
+    }
+
+    // a broken file shouldn't be able to take a worker down with it
+    fn run_catching_panics(self) -> Decoded {
+        let asset = self.asset();
+        panic::catch_unwind(AssertUnwindSafe(|| self.run()))
+            .unwrap_or_else(|payload| Decoded::Failed(asset, Error::Decode(panic_message(payload))))
+    }
+
+    fn run(self) -> Decoded {
+        match self {
+            Job::Texture { texture, options, path } => {
+                let data = std::fs::read(&path)
+                    .map_err(Error::from)
+                    .and_then(|bytes| {
+                        #[cfg(feature = "ktx2")]
+                        if ktx2::is_ktx2(&bytes) {
+                            return ktx2::parse_ktx2(&bytes).map(TextureData::Compressed);
+                        }
+                        images::load_from_file_contents(&bytes, &path).map(TextureData::Image)
+                    });
+                Decoded::Texture(texture, options, data)
+            }
+            Job::Model { model, path } => Decoded::Model(model, meshes::load_gltf(&path)),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("Loading panicked: {}", message)
+}
+
+pub(in crate::rendering) struct LoadingModel {
+    pub render_pipeline: PipelineHandle,
+    // set once it's loaded
+    pub bound: Option<BoundModel>,
+    pub objects: Vec<GltfObject>,
+}
+
+pub(in crate::rendering) struct AssetServer {
+    // workers are only started by the first load
+    jobs: Option<Sender<Job>>,
+    results_sender: Sender<Decoded>,
+    results: Receiver<Decoded>,
+    // one entry per asset that's still alive, removed by forget
+    states: HashMap<AssetId, LoadState>,
+    batch: LoadProgress,
+    pub models: HandleSlab<ModelHandle, LoadingModel>,
+}
+
+impl AssetServer {
+    pub fn new() -> Self {
+        let (results_sender, results) = channel();
+        Self {
+            jobs: None,
+            results_sender,
+            results,
+            states: HashMap::new(),
+            batch: LoadProgress::default(),
+            models: HandleSlab::new(),
+        }
+    }
+
+    /// The texture should be showing a placeholder until it's loaded.
+    pub fn load_texture(&mut self, texture: TextureHandle, path: &str, options: TextureOptions) {
+        self.start(AssetId::Texture(texture));
+        self.send(Job::Texture {
+            texture,
+            options,
+            path: path.to_string(),
+        });
+    }
+
+    pub fn load_model(&mut self, path: &str, render_pipeline: PipelineHandle) -> ModelHandle {
+        let model = self.models.insert(LoadingModel {
+            render_pipeline,
+            bound: None,
+            objects: Vec::new(),
+        });
+        self.start(AssetId::Model(model));
+        self.send(Job::Model {
+            model,
+            path: path.to_string(),
+        });
+        model
+    }
+
+    /// Something the workers finished, if there is anything
+    pub fn try_receive(&self) -> Option<Decoded> {
+        self.results.try_recv().ok()
+    }
+
+    fn start(&mut self, asset: AssetId) {
+        if self.batch.is_done() {
+            self.batch = LoadProgress::default();
+        }
+        self.batch.loading += 1;
+        self.states.insert(asset, LoadState::Loading);
+    }
+
+    /// Records how loading the asset ended. Does nothing if it isn't loading anymore.
+    pub fn finish(&mut self, asset: AssetId, result: Result<()>) {
+        let state = match self.states.get_mut(&asset) {
+            Some(state @ LoadState::Loading) => state,
+            _ => return,
+        };
+        self.batch.loading -= 1;
+        *state = match result {
+            Ok(()) => {
+                self.batch.loaded += 1;
+                LoadState::Loaded
+            },
+            Err(e) => {
+                self.batch.failed += 1;
+                LoadState::Failed(e)
+            },
+        };
+    }
+
+    /// Stops tracking an asset, like when it's deleted. If it was still loading,
+    /// it's taken out of the batch.
+    pub fn forget(&mut self, asset: AssetId) {
+        if let Some(LoadState::Loading) = self.states.remove(&asset) {
+            self.batch.loading -= 1;
+        }
+    }
+
+    pub fn state(&self, asset: AssetId) -> Option<&LoadState> {
+        self.states.get(&asset)
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        self.batch
+    }
+
+    fn send(&mut self, job: Job) {
+        let results_sender = &self.results_sender;
+        let jobs = self.jobs.get_or_insert_with(|| {
+            let (sender, receiver) = channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_WORKERS);
+            for i in 0..workers {
+                let receiver = receiver.clone();
+                let results = results_sender.clone();
+                thread::Builder::new()
+                    .name(format!("asset loader {}", i))
+                    .spawn(move || {
+                        // the channels close when the server is dropped, which ends the thread.
+                        // jobs run outside the lock, but if it ever gets poisoned the receiver is still fine
+                        let next_job = || receiver.lock().unwrap_or_else(PoisonError::into_inner).recv().ok();
+                        while let Some(job) = next_job() {
+                            if results.send(job.run_catching_panics()).is_err() {
+                                break;
+                            }
+                        }
+                    })
+                    .expect("Failed to start asset loading thread");
+            }
+            sender
+        });
+        // the workers only stop once this sender is gone, so this can't fail
+        let _ = jobs.send(job);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the server never looks the handles up, they only have to be different
+    fn start_textures<const N: usize>(server: &mut AssetServer) -> [AssetId; N] {
+        let mut textures = HandleSlab::<TextureHandle, ()>::new();
+        [(); N].map(|_| {
+            let asset = AssetId::Texture(textures.insert(()));
+            server.start(asset);
+            asset
+        })
+    }
+
+    #[test]
+    fn progress_starts_over_once_a_batch_is_done() {
+        let mut server = AssetServer::new();
+        let [a, b] = start_textures(&mut server);
+        server.finish(a, Ok(()));
+        assert_eq!(server.progress(), LoadProgress { loading: 1, loaded: 1, failed: 0 });
+        server.finish(b, Err(Error::Decode("broken".into())));
+        assert_eq!(server.progress(), LoadProgress { loading: 0, loaded: 1, failed: 1 });
+        assert!(server.progress().is_done());
+
+        let [c] = start_textures(&mut server);
+        assert_eq!(server.progress(), LoadProgress { loading: 1, loaded: 0, failed: 0 });
+        server.finish(c, Ok(()));
+        assert_eq!(server.progress().fraction(), 1.0);
+    }
+
+    #[test]
+    fn forgotten_assets_leave_the_batch() {
+        let mut server = AssetServer::new();
+        let [a, b] = start_textures(&mut server);
+        server.forget(a);
+        assert_eq!(server.progress(), LoadProgress { loading: 1, loaded: 0, failed: 0 });
+        assert!(server.state(a).is_none());
+
+        // a result for a forgotten asset that comes in late is ignored
+        server.finish(a, Ok(()));
+        assert!(server.state(a).is_none());
+        server.finish(b, Ok(()));
+        assert_eq!(server.progress(), LoadProgress { loading: 0, loaded: 1, failed: 0 });
+        assert!(matches!(server.state(b), Some(LoadState::Loaded)));
+    }
+}
@@ -1,24 +1,117 @@
-/**
+/*!
  * a `Camera` is an abstract Camera. it cannot be used directly for rendering.
  * `Camera` might be useful for math, or for having multiple cameras that you
  * swap between.
  * 
  * a `RenderableCamera` is a camera with buffers and bindings that can actually
- * be used for rendering. typically, only one instance of RenderableCamera should
- * exist.
+ * be used for rendering. every one of them renders the scene into its own
+ * viewport each frame, so several can exist for split-screen or editor views.
  */
 
+use crate::handle::TextureHandle;
 use cgmath;
 use wgpu::util::DeviceExt;
 
+/// How a camera projects the scene onto the screen. The aspect ratio isn't part
+/// of this, it always follows the size of what's being rendered to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Things further away look smaller. `fovy` is the vertical field of view.
+    Perspective {
+        fovy: cgmath::Deg<f32>,
+        znear: f32,
+        zfar: f32,
+    },
+    /// Things keep their size regardless of distance. `height` is how much of the
+    /// world fits vertically on screen, in world units.
+    Orthographic {
+        height: f32,
+        znear: f32,
+        zfar: f32,
+    },
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective {
+            fovy: cgmath::Deg(60.0),
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
+}
+
+// cgmath builds projections for a -1..1 depth range, wgpu wants 0..1
+pub(in crate::rendering) const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+impl Projection {
+    /// The projection matrix for a target with the given aspect ratio. Depth goes
+    /// from 0 at `znear` to 1 at `zfar`, like wgpu expects.
+    pub fn matrix(&self, aspect: f32) -> cgmath::Matrix4<f32> {
+        let projection = match *self {
+            Projection::Perspective { fovy, znear, zfar } => cgmath::perspective(fovy, aspect, znear, zfar),
+            Projection::Orthographic { height, znear, zfar } => {
+                let half_height = height / 2.0;
+                let half_width = half_height * aspect;
+                cgmath::ortho(-half_width, half_width, -half_height, half_height, znear, zfar)
+            },
+        };
+        OPENGL_TO_WGPU_MATRIX * projection
+    }
+}
+
+/// The part of the render target a camera draws into, as fractions of the
+/// target's size, so it keeps its place when the window is resized.
+/// (0, 0) is the top left corner.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for Viewport {
+    /// The whole render target
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        }
+    }
+}
+
+impl Viewport {
+    /// The viewport in pixels of a target of the given size, clamped to the target
+    pub fn to_pixels(&self, target_width: u32, target_height: u32) -> (u32, u32, u32, u32) {
+        let to_pixel = |fraction: f32, size: u32| ((fraction.max(0.0) * size as f32).round() as u32).min(size);
+        let x = to_pixel(self.x, target_width);
+        let y = to_pixel(self.y, target_height);
+        let width = to_pixel(self.width, target_width).min(target_width - x);
+        let height = to_pixel(self.height, target_height).min(target_height - y);
+        (x, y, width, height)
+    }
+}
+
+/// Layer mask that makes a camera see everything
+pub const ALL_LAYERS: u32 = u32::MAX;
+
 pub(in crate::rendering) struct Camera {
     pub pos: cgmath::Point3<f32>, // camera position
     pub target: cgmath::Point3<f32>, // point the camera looks at
     pub up: cgmath::Vector3<f32>, // "up" vector for deciding camera roll
-    aspect: f32,
-    fovy: f32, // degrees
-    znear: f32,
-    zfar: f32,
+    pub aspect: f32, // width / height of the render target, kept up to date by RenderingInstance
+    pub projection: Projection,
+    pub viewport: Viewport,
+    pub layers: u32, // only instances sharing a bit with this are drawn
+    pub render_target: Option<TextureHandle>, // texture drawn into, or None for the frame itself
 }
 
 pub(in crate::rendering) struct RenderableCamera {
@@ -27,52 +120,94 @@ pub(in crate::rendering) struct RenderableCamera {
     #[allow(dead_code)] // buffer must be stored since bind_group references it
     // TODO make sure this actually has to be stored
     buffer: wgpu::Buffer,
-    // TODO only one bind_group_layout will ever exist per program,
-    // so bind_group_layout should actually go in RenderingInstance
-    // instead of here. even if there are two cameras, they should
-    // have the same bind_group_layout.
-    pub bind_group_layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
 }
 
 impl Camera {
-    pub fn new() -> Self {
+    pub fn new(aspect: f32) -> Self {
         Self {
             pos: (0.0, 0.0, -10.0).into(),
             target: (0.0, 0.0, 0.0).into(),
             up: (0.0, 1.0, 0.0).into(),
-            aspect: 1.0, // TODO aspect is wrong, should by window width/height
-            fovy: 60.0,
-            znear: 0.1,
-            zfar: 100.0,
+            aspect,
+            projection: Projection::default(),
+            viewport: Viewport::default(),
+            layers: ALL_LAYERS,
+            render_target: None,
+        }
+    }
+
+    /// Recomputes the aspect ratio for the camera's viewport on a target of the given size
+    pub fn fit_to_target(&mut self, target_width: u32, target_height: u32) {
+        let (_, _, width, height) = self.viewport.to_pixels(target_width, target_height);
+        if width > 0 && height > 0 {
+            self.aspect = width as f32 / height as f32;
         }
     }
     
     pub fn matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.pos, self.target, self.up);
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
-        proj * view
+        self.projection.matrix(self.aspect) * view
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(self.matrix())
+    }
+
+    /// A sphere around what the camera sees, up to `max_distance` past the near plane.
+    /// `None` if the camera's matrix can't be inverted.
+    pub fn view_bounds(&self, max_distance: f32) -> Option<(cgmath::Point3<f32>, f32)> {
+        use cgmath::{EuclideanSpace, InnerSpace, MetricSpace, SquareMatrix};
+        let inverse = self.matrix().invert()?;
+        let unproject = |x: f32, y: f32, z: f32| cgmath::Point3::from_homogeneous(inverse * cgmath::Vector4::new(x, y, z, 1.0));
+        let mut corners = Vec::with_capacity(8);
+        for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+            let near = unproject(x, y, 0.0);
+            let far = unproject(x, y, 1.0);
+            let length = (far - near).magnitude();
+            corners.push(near);
+            corners.push(near + (far - near) * (max_distance / length).min(1.0));
+        }
+        let center = cgmath::Point3::centroid(&corners);
+        let radius = corners.iter().map(|corner| corner.distance(center)).fold(0.0, f32::max);
+        Some((center, radius))
+    }
+}
+
+/// The volume a camera can see, as six planes facing inwards.
+pub(in crate::rendering) struct Frustum {
+    planes: [cgmath::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // gribb & hartmann: every plane is a sum or difference of two rows of the matrix.
+    // depth goes from 0 to 1, so the near plane is just the z row
+    pub fn from_matrix(matrix: cgmath::Matrix4<f32>) -> Self {
+        use cgmath::{InnerSpace, Matrix};
+        let m = matrix.transpose(); // rows of the matrix as columns
+        let planes = [m.w + m.x, m.w - m.x, m.w + m.y, m.w - m.y, m.z, m.w - m.z]
+            .map(|plane| plane / plane.truncate().magnitude());
+        Self {
+            planes,
+        }
+    }
+
+    /// False only if the sphere is entirely outside
+    pub fn intersects_sphere(&self, center: cgmath::Point3<f32>, radius: f32) -> bool {
+        self.planes.iter().all(|plane| {
+            plane.x * center.x + plane.y * center.y + plane.z * center.z + plane.w >= -radius
+        })
     }
 }
 
 impl RenderableCamera {
-    pub fn new(device: &wgpu::Device) -> Self {
-        let camera = Camera::new();
-        let uniform = CameraUniform {
-            matrix: camera.matrix().into(),
-        };
-        let buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("camera_buffer"),
-                contents: bytemuck::cast_slice(&[uniform]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            }
-        );
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+    /// The layout every camera's bind group has. Only one is needed, no matter how many cameras there are.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -82,9 +217,24 @@ impl RenderableCamera {
                 }
             ],
             label: Some("camera_bind_group_layout"),
-        });
+        })
+    }
+
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, aspect: f32) -> Self {
+        let camera = Camera::new(aspect);
+        let uniform = CameraUniform {
+            view_proj: camera.matrix().into(),
+            view_position: camera.pos.to_homogeneous().into(),
+        };
+        let buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("camera_buffer"),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
@@ -98,13 +248,13 @@ impl RenderableCamera {
             camera,
             uniform,
             buffer,
-            bind_group_layout,
             bind_group,
         }
     }
 
     pub fn update(&mut self, queue: &mut wgpu::Queue) {
-        self.uniform.matrix = self.camera.matrix().into();
+        self.uniform.view_proj = self.camera.matrix().into();
+        self.uniform.view_position = self.camera.pos.to_homogeneous().into();
         // TODO this is not maximum efficiency. look into map_write_async
         queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
     }
@@ -113,5 +263,82 @@ impl RenderableCamera {
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(in crate::rendering) struct CameraUniform {
-    matrix: [[f32; 4]; 4],
-}
\ No newline at end of file
+    // only takes world space to clip space. anything shaded in world space
+    // (normals, light directions) must never go through this.
+    view_proj: [[f32; 4]; 4],
+    view_position: [f32; 4], // camera position, for lighting
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, Vector3, Vector4};
+
+    fn depth(projection: Projection, distance: f32) -> f32 {
+        // the camera looks down -z before it's projected
+        let clip = projection.matrix(1.0) * Vector4::new(0.0, 0.0, -distance, 1.0);
+        clip.z / clip.w
+    }
+
+    #[test]
+    fn projections_use_a_zero_to_one_depth_range() {
+        let perspective = Projection::Perspective { fovy: cgmath::Deg(60.0), znear: 0.5, zfar: 50.0 };
+        let orthographic = Projection::Orthographic { height: 10.0, znear: 0.5, zfar: 50.0 };
+        for projection in [perspective, orthographic] {
+            assert!(depth(projection, 0.5).abs() < 1e-5, "{:?}", projection);
+            assert!((depth(projection, 50.0) - 1.0).abs() < 1e-5, "{:?}", projection);
+        }
+    }
+
+    #[test]
+    fn frustum_near_plane_matches_znear() {
+        let mut camera = Camera::new(1.0);
+        camera.pos = Point3::new(0.0, 0.0, 0.0);
+        camera.target = Point3::new(0.0, 0.0, -1.0);
+        camera.projection = Projection::Perspective { fovy: cgmath::Deg(60.0), znear: 1.0, zfar: 10.0 };
+        let frustum = camera.frustum();
+        assert!(frustum.intersects_sphere(Point3::new(0.0, 0.0, -1.1), 0.05));
+        assert!(!frustum.intersects_sphere(Point3::new(0.0, 0.0, -0.9), 0.05));
+    }
+
+    #[test]
+    fn frustum_tells_spheres_inside_straddling_and_outside_each_plane() {
+        // sees x and y from -2 to 2, and z from -1 to -10
+        let mut camera = Camera::new(1.0);
+        camera.pos = Point3::new(0.0, 0.0, 0.0);
+        camera.target = Point3::new(0.0, 0.0, -1.0);
+        camera.projection = Projection::Orthographic { height: 4.0, znear: 1.0, zfar: 10.0 };
+        let frustum = camera.frustum();
+        let radius = 0.5;
+        assert!(frustum.intersects_sphere(Point3::new(0.0, 0.0, -5.0), radius));
+        // a point on each plane, and which way is out
+        let planes = [
+            (Point3::new(-2.0, 0.0, -5.0), Vector3::new(-1.0, 0.0, 0.0)),
+            (Point3::new(2.0, 0.0, -5.0), Vector3::new(1.0, 0.0, 0.0)),
+            (Point3::new(0.0, -2.0, -5.0), Vector3::new(0.0, -1.0, 0.0)),
+            (Point3::new(0.0, 2.0, -5.0), Vector3::new(0.0, 1.0, 0.0)),
+            (Point3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 0.0, 1.0)),
+            (Point3::new(0.0, 0.0, -10.0), Vector3::new(0.0, 0.0, -1.0)),
+        ];
+        for (on_plane, out) in planes {
+            assert!(frustum.intersects_sphere(on_plane - out * 0.6, radius), "inside {:?}", on_plane);
+            assert!(frustum.intersects_sphere(on_plane + out * 0.4, radius), "straddling {:?}", on_plane);
+            assert!(!frustum.intersects_sphere(on_plane + out * 0.6, radius), "outside {:?}", on_plane);
+        }
+    }
+
+    #[test]
+    fn view_bounds_stop_at_the_max_distance() {
+        use cgmath::MetricSpace;
+        let mut camera = Camera::new(1.0);
+        camera.pos = Point3::new(0.0, 0.0, 0.0);
+        camera.target = Point3::new(0.0, 0.0, -1.0);
+        camera.projection = Projection::Perspective { fovy: cgmath::Deg(60.0), znear: 1.0, zfar: 1000.0 };
+        let (center, radius) = camera.view_bounds(10.0).unwrap();
+        assert!(center.distance(Point3::new(0.0, 0.0, -5.0)) < 5.0);
+        assert!(radius < 15.0);
+        // both a point just past the near plane and one at the max distance are inside
+        assert!(center.distance(Point3::new(0.0, 0.0, -1.0)) <= radius);
+        assert!(center.distance(Point3::new(0.0, 0.0, -10.0)) <= radius);
+    }
+}
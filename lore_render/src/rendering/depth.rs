@@ -0,0 +1,39 @@
+/*!
+ * the depth buffer that goes along with a render target. it has to match the
+ * size of the color target it's used with, so it's recreated whenever the
+ * surface is resized.
+ */
+
+use wgpu::{Device, TextureFormat};
+
+pub(crate) const DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+
+pub(crate) struct DepthTexture {
+    #[allow(dead_code)] // the view is what gets used, but the texture has to outlive it
+    texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+impl DepthTexture {
+    pub fn new(device: &Device, width: u32, height: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            label: Some("Depth Texture"),
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self {
+            texture,
+            view,
+        }
+    }
+}
@@ -1,10 +1,33 @@
+#[cfg(feature = "ktx2")]
 use crate::{
+    asset_loading::ktx2::CompressedImage,
+    rendering::textures::block_texture_format,
+};
+use crate::{
+    error::{Error, Result},
+    handle::{
+        HandleSlab, MeshHandle, TextureHandle, PipelineHandle,
+        GlyphBrushHandle, TextBoxHandle, InstanceHandle, InstanceKey,
+        LightHandle, MaterialHandle, CameraHandle, ModelHandle,
+    },
     mesh::{
-        Mesh, Vertex,
+        Mesh, Vertex, BoundingSphere,
+    },
+    rendering::{
+        assets::{AssetServer, AssetId, LoadState, LoadProgress, Decoded, TextureData},
+        camera::{RenderableCamera, Projection, Viewport, Frustum},
+        depth::{DepthTexture, DEPTH_FORMAT},
+        instances::InstanceStore,
+        picking::{PickHit, Ray},
+        lights::{Light, RenderableLights},
+        material::{Material, LoadedMaterial, FallbackTextures, create_material_bind_group_layout},
+        model::BoundModel,
+        shadows::{ShadowBounds, Sphere, enclosing_sphere, DIRECTIONAL_SHADOW_DISTANCE},
+        textures::{TextureOptions, MipmapGenerator},
     },
-    rendering::camera::RenderableCamera,
 };
 use std::{
+    collections::HashSet,
     mem::size_of,
     str,
 };
@@ -12,7 +35,6 @@ use cgmath::{
     prelude::*,
     *,
 };
-use slab::Slab;
 use wgpu::{
     *,
     util::{
@@ -27,32 +49,75 @@ use wgpu_glyph::GlyphBrush;
 use image::GenericImageView;
 use std::io::Read;
 use futures::task::SpawnExt;
+use pollster::block_on;
+
+/// Features used when the adapter has them: compressed texture formats.
+const OPTIONAL_FEATURES: Features = Features::from_bits_truncate(
+    Features::TEXTURE_COMPRESSION_BC.bits() | Features::TEXTURE_COMPRESSION_ETC2.bits()
+);
+
+/// Format used for offscreen frames. Readback assumes 4 bytes per pixel.
+const OFFSCREEN_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+/// What every target shows where no camera drew anything.
+const CLEAR_COLOR: wgpu::Color = wgpu::Color {
+    r: 0.1,
+    g: 0.2,
+    b: 0.3,
+    a: 1.0,
+};
+
+/// Where finished frames end up. A window surface gets presented to the screen,
+/// an offscreen texture is only ever read back through `draw_to_image`.
+enum FrameTarget {
+    Surface(Surface),
+    Offscreen(wgpu::Texture),
+}
 
 pub struct RenderingInstance {
     // TODO oh god, please split this into multiple files you idiot
-    surface: Surface,
+    target: FrameTarget,
     device: Device,
     queue: Queue,
     config: SurfaceConfiguration,
     size: PhysicalSize<u32>,
+    depth_texture: DepthTexture,
 
     text_staging_belt: StagingBelt,
     local_pool: futures::executor::LocalPool,
     local_spawner: futures::executor::LocalSpawner,
 
     render_pipeline_layout: PipelineLayout,
-    texture_bind_group_layout: BindGroupLayout,
-
-    render_pipelines: Slab<RenderPipeline>,
-    loaded_meshes: Slab<LoadedMesh>,
-    textures: Slab<Texture>,
-    glyph_brushes: Slab<GlyphBrush<()>>,
-    text_instances: Slab<TextInstance>,
-    camera: RenderableCamera,
+    material_bind_group_layout: BindGroupLayout,
+
+    render_pipelines: HandleSlab<PipelineHandle, LoadedPipeline>,
+    loaded_meshes: HandleSlab<MeshHandle, LoadedMesh>,
+    textures: HandleSlab<TextureHandle, Texture>,
+    mipmaps: MipmapGenerator,
+    // magenta checkerboard, the base color of the default material
+    default_texture: TextureHandle,
+    // fill in for textures a material doesn't have
+    fallback_textures: FallbackTextures,
+    materials: HandleSlab<MaterialHandle, LoadedMaterial>,
+    default_material: MaterialHandle,
+    glyph_brushes: HandleSlab<GlyphBrushHandle, GlyphBrush<()>>,
+    text_instances: HandleSlab<TextBoxHandle, TextInstance>,
+    camera_bind_group_layout: BindGroupLayout,
+    // every camera renders the scene into its own viewport, in the order they were created
+    cameras: HandleSlab<CameraHandle, RenderableCamera>,
+    // created along with the instance and can't be removed
+    main_camera: CameraHandle,
+    lights: RenderableLights,
+    // sphere around every shadow caster, only recomputed when instances change
+    caster_bounds: Option<Sphere>,
+    caster_bounds_dirty: bool,
+    culling_stats: CullingStats,
+    // decodes files in the background, see load_texture and load_model
+    assets: AssetServer,
 }
 
 impl RenderingInstance {
-    pub(crate) async fn new(window: &Window) -> Self {
+    pub(crate) async fn new(window: &Window) -> Result<Self> {
         // Most of this is self-explanatory, but to the extent that it's not,
         // look up the wgpu-rs book tutorial. It's not too divergent from how
         // things are done there.
@@ -65,103 +130,197 @@ impl RenderingInstance {
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             }
-        ).await.expect("Failed to create WGPU adapter.");
+        ).await.ok_or_else(|| Error::Gpu("Failed to create WGPU adapter.".into()))?;
         let (device, queue) = adapter.request_device(
             &DeviceDescriptor {
-                features: Features::empty(),
+                features: adapter.features() & OPTIONAL_FEATURES,
                 limits: Limits::default(),
                 label: None,
             },
             None
-        ).await.expect("Failed to create WGPU Device.");
+        ).await.map_err(|e| Error::Gpu(e.to_string()))?;
+        let format = surface.get_preferred_format(&adapter)
+            .ok_or_else(|| Error::Gpu("Surface is incompatible with the adapter.".into()))?;
         let config = SurfaceConfiguration {
             usage: TextureUsages::RENDER_ATTACHMENT,
-            format: surface.get_preferred_format(&adapter).unwrap(),
+            format,
             width: size.width,
             height: size.height,
             present_mode: PresentMode::Fifo,
         };
         surface.configure(&device, &config);
 
-        let camera = RenderableCamera::new(&device);
-        let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    // This should match the filterable field of the
-                    // corresponding Texture entry above.
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-            ],
-            label: None,
-        });
+        Ok(Self::from_device(device, queue, config, FrameTarget::Surface(surface)))
+    }
+
+    /// Creates a `RenderingInstance` with no window attached. Frames are rendered
+    /// into an offscreen texture and can be read back with `draw_to_image`.
+    /// Falls back to a software adapter if no hardware adapter is available,
+    /// so this works on machines without a GPU or display.
+    pub fn new_headless(width: u32, height: u32) -> Result<Self> {
+        block_on(Self::new_headless_async(width, height))
+    }
+
+    async fn new_headless_async(width: u32, height: u32) -> Result<Self> {
+        let instance = wgpu::Instance::new(Backends::all());
+        let mut adapter = instance.request_adapter(
+            &RequestAdapterOptions {
+                power_preference: PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            }
+        ).await;
+        if adapter.is_none() {
+            adapter = instance.request_adapter(
+                &RequestAdapterOptions {
+                    power_preference: PowerPreference::default(),
+                    compatible_surface: None,
+                    force_fallback_adapter: true,
+                }
+            ).await;
+        }
+        let adapter = adapter.ok_or_else(|| Error::Gpu("Failed to create WGPU adapter.".into()))?;
+        let (device, queue) = adapter.request_device(
+            &DeviceDescriptor {
+                features: adapter.features() & OPTIONAL_FEATURES,
+                limits: Limits::default(),
+                label: None,
+            },
+            None
+        ).await.map_err(|e| Error::Gpu(e.to_string()))?;
+        // there's no surface to configure, but the config is still where
+        // the rest of the engine looks for the output format and size
+        let config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format: OFFSCREEN_FORMAT,
+            width,
+            height,
+            present_mode: PresentMode::Fifo,
+        };
+        check_texture_size(&device, width, height)?;
+        let texture = create_offscreen_texture(&device, &config);
+
+        Ok(Self::from_device(device, queue, config, FrameTarget::Offscreen(texture)))
+    }
+
+    fn from_device(device: Device, queue: Queue, config: SurfaceConfiguration, target: FrameTarget) -> Self {
+        let size = PhysicalSize::new(config.width, config.height);
+        let depth_texture = DepthTexture::new(&device, config.width, config.height);
+        let camera_bind_group_layout = RenderableCamera::create_bind_group_layout(&device);
+        let mut cameras = HandleSlab::new();
+        let main_camera = cameras.insert(
+            RenderableCamera::new(&device, &camera_bind_group_layout, config.width as f32 / config.height as f32)
+        );
+        let lights = RenderableLights::new(&device);
+        let material_bind_group_layout = create_material_bind_group_layout(&device);
         let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: None,
             bind_group_layouts: &[
-                &texture_bind_group_layout,
-                &camera.bind_group_layout,
+                &material_bind_group_layout,
+                &camera_bind_group_layout,
+                &lights.bind_group_layout,
             ],
             push_constant_ranges: &[],
         });
 
+        let mut textures = HandleSlab::new();
+        let mut mipmaps = MipmapGenerator::new(&device);
+        let mut builtin_texture = |img, options| textures.insert(
+            create_gpu_texture(&device, &queue, &mut mipmaps, img, options).expect("Failed to create builtin texture")
+        );
+        let default_texture = builtin_texture(crate::asset_loading::images::default_texture(), TextureOptions::pixelated());
+        let fallback_textures = FallbackTextures {
+            white: builtin_texture(crate::asset_loading::images::white_texture(), TextureOptions::pixelated()),
+            flat_normal: builtin_texture(crate::asset_loading::images::flat_normal_texture(), TextureOptions {
+                srgb: false,
+                ..TextureOptions::pixelated()
+            }),
+        };
+        let mut materials = HandleSlab::new();
+        let default_material = materials.insert(
+            LoadedMaterial::new(
+                &device,
+                &material_bind_group_layout,
+                Material {
+                    base_color_texture: Some(default_texture),
+                    ..Material::default()
+                },
+                &textures,
+                &fallback_textures,
+            ).expect("Failed to create default material")
+        );
+
         let staging_belt = wgpu::util::StagingBelt::new(1024);
         let local_pool = futures::executor::LocalPool::new();
         let local_spawner = local_pool.spawner();
 
-        let mut ret = Self {
-            surface,
+        Self {
+            target,
             device,
             queue,
             config,
             size,
+            depth_texture,
 
             text_staging_belt: staging_belt,
             local_pool,
             local_spawner,
 
             render_pipeline_layout,
-            texture_bind_group_layout,
-            render_pipelines: Slab::new(),
-            loaded_meshes: Slab::new(),
-            textures: Slab::new(),
-            glyph_brushes: Slab::new(),
-            text_instances: Slab::new(),
-            camera,
-        };
-
-        ret.create_texture(crate::asset_loading::images::default_texture());
-
-        ret
+            material_bind_group_layout,
+            render_pipelines: HandleSlab::new(),
+            loaded_meshes: HandleSlab::new(),
+            textures,
+            mipmaps,
+            default_texture,
+            fallback_textures,
+            materials,
+            default_material,
+            glyph_brushes: HandleSlab::new(),
+            text_instances: HandleSlab::new(),
+            camera_bind_group_layout,
+            cameras,
+            main_camera,
+            lights,
+            caster_bounds: None,
+            caster_bounds_dirty: false,
+            culling_stats: CullingStats::default(),
+            assets: AssetServer::new(),
+        }
     }
 
     pub(crate) fn resize(&mut self, new_size: PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            self.refresh_surface_configuration();
         } // TODO else panic?
     }
 
     pub(crate) fn refresh_surface_configuration(&mut self) {
         self.config.width = self.size.width;
         self.config.height = self.size.height;
-        self.surface.configure(&self.device, &self.config);
+        for (_, camera) in self.cameras.iter_mut() {
+            if camera.camera.render_target.is_none() {
+                camera.camera.fit_to_target(self.size.width, self.size.height);
+            }
+        }
+        match &mut self.target {
+            FrameTarget::Surface(surface) => surface.configure(&self.device, &self.config),
+            FrameTarget::Offscreen(texture) => *texture = create_offscreen_texture(&self.device, &self.config),
+        }
+        self.depth_texture = DepthTexture::new(&self.device, self.config.width, self.config.height);
+    }
+
+    pub fn create_render_pipeline(&mut self, shader_src: &str) -> Result<PipelineHandle> {
+        self.create_render_pipeline_with_options(shader_src, PipelineOptions::default())
     }
 
-    pub fn create_render_pipeline(&mut self, shader_src: &str) -> usize {
+    /// Fails with `Error::Gpu` if the shader doesn't compile or doesn't fit the
+    /// engine's vertex and bind group layouts.
+    pub fn create_render_pipeline_with_options(&mut self, shader_src: &str, options: PipelineOptions) -> Result<PipelineHandle> {
+        // wgpu reports validation errors through a callback that panics by default,
+        // the error scope catches them so they can be returned instead
+        self.device.push_error_scope(ErrorFilter::Validation);
         let shader = self.create_shader_module(shader_src);
         let pipeline = self.device.create_render_pipeline(&RenderPipelineDescriptor{
             label: None,
@@ -183,7 +342,13 @@ impl RenderingInstance {
                     write_mask: ColorWrites::ALL,
                 }]
             }),
-            depth_stencil: None,
+            depth_stencil: Some(DepthStencilState {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: options.depth_write,
+                depth_compare: CompareFunction::Less,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
             multisample: MultisampleState {
                 count: 1,
                 mask: !0,
@@ -200,7 +365,13 @@ impl RenderingInstance {
                 conservative: false,
             },
         });
-        self.render_pipelines.insert(pipeline)
+        if let Some(error) = block_on(self.device.pop_error_scope()) {
+            return Err(error.into());
+        }
+        Ok(self.render_pipelines.insert(LoadedPipeline {
+            pipeline,
+            screen_space: options.screen_space,
+        }))
     }
 
     fn create_shader_module(&mut self, shader_src: &str) -> ShaderModule {
@@ -210,15 +381,24 @@ impl RenderingInstance {
         })
     }
 
-    pub fn create_default_render_pipeline(&mut self) -> usize {
+    pub fn create_default_render_pipeline(&mut self) -> Result<PipelineHandle> {
         self.create_render_pipeline(include_str!("../../shaders/default_shader.wgsl"))
     } // TODO this function belongs elsewhere, somewhere closer to the API level instead of backend
 
-    pub fn create_default_gui_render_pipeline(&mut self) -> usize {
-        self.create_render_pipeline(include_str!("../../shaders/gui_shader.wgsl"))
+    pub fn create_default_gui_render_pipeline(&mut self) -> Result<PipelineHandle> {
+        let options = PipelineOptions {
+            screen_space: true,
+            ..PipelineOptions::default()
+        };
+        self.create_render_pipeline_with_options(include_str!("../../shaders/gui_shader.wgsl"), options)
     } // TODO this function belongs elsewhere, somewhere closer to the API level instead of backend
 
-    pub fn bind_mesh(&mut self, mesh: &Mesh, render_pipeline: usize, texture_id: Option<usize>) -> usize {
+    /// Fails with `Error::Decode` if any of the mesh's indices is past its vertices.
+    pub fn bind_mesh(&mut self, mesh: &Mesh, render_pipeline: PipelineHandle, material: MaterialHandle) -> Result<MeshHandle> {
+        self.render_pipelines.try_get(render_pipeline)?;
+        self.materials.try_get(material)?;
+        mesh.check_indices()?;
+
         let vertex_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(&mesh.vertices),
@@ -229,210 +409,931 @@ impl RenderingInstance {
             contents: bytemuck::cast_slice(&mesh.indices),
             usage: BufferUsages::INDEX,
         });
-        let instance_buffer = self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: &[],
-            usage: BufferUsages::VERTEX,
-        });
-
-        self.loaded_meshes.insert(
+        Ok(self.loaded_meshes.insert(
         LoadedMesh {
                 vertex_buffer,
                 index_buffer,
                 num_indices: mesh.indices.len() as u32,
+                bounds: mesh.bounding_sphere(),
+                positions: mesh.vertices.iter().map(|v| Point3::from(v.position)).collect(),
+                indices: mesh.indices.clone(),
                 render_pipeline,
-                instances: Slab::with_capacity(5),
-                instance_buffer,
-                texture_id,
+                instances: InstanceStore::new(&self.device),
+                material,
             }
-        )
+        ))
+    }
+
+    pub fn create_object_instance(&mut self, mesh: MeshHandle, instance: ObjectInstance) -> Result<InstanceHandle> {
+        let loaded_mesh = self.loaded_meshes.try_get_mut(mesh)?;
+        Ok(InstanceHandle {
+            mesh,
+            key: loaded_mesh.add_instance(instance),
+        })
     }
 
-    pub fn create_object_instance(&mut self, mesh: usize, instance: ObjectInstance) -> (usize, usize) {
-        (mesh, self.loaded_meshes[mesh].add_instance(instance))
+    pub fn remove_object_instance(&mut self, instance_id: InstanceHandle) -> Result<()> {
+        let loaded_mesh = self.loaded_meshes.try_get_mut(instance_id.mesh)?;
+        loaded_mesh.remove_instance(instance_id.key)
+    }
+
+    pub fn modify_instance(&mut self, instance_id: InstanceHandle, fun: fn(&mut ObjectInstance) -> ()) -> Result<()> {
+        let instance_obj = self.get_instance_mut(instance_id)?;
+        fun(instance_obj);
+        Ok(())
     }
 
-    pub fn remove_object_instance(&mut self, instance_id: (usize, usize)) {
-        let (mesh, instance) = instance_id;
-        self.loaded_meshes[mesh].remove_instance(instance);
+    /// The instance is uploaded again at the next frame, whether it was changed or not.
+    pub fn get_instance_mut(&mut self, instance_id: InstanceHandle) -> Result<&mut ObjectInstance> {
+        self.loaded_meshes.try_get_mut(instance_id.mesh)?
+            .instance_mut(instance_id.key)
     }
 
-    pub fn modify_instance(&mut self, instance_id: (usize, usize), fun: fn(&mut ObjectInstance) -> ()) {
-        let (mesh, instance) = instance_id;
-        let mut instance_obj = self.loaded_meshes[mesh].instances.get_mut(instance).unwrap();
-        fun(&mut instance_obj);
+    pub fn read_instance(&mut self, instance_id: InstanceHandle) -> Result<&ObjectInstance> {
+        self.loaded_meshes.try_get(instance_id.mesh)?
+            .instances.get(instance_id.key)
+    }
+
+    /// Adds a camera that draws the whole screen and sees every layer, until told otherwise.
+    pub fn create_camera(&mut self) -> CameraHandle {
+        let mut camera = RenderableCamera::new(&self.device, &self.camera_bind_group_layout, 1.0);
+        camera.camera.fit_to_target(self.config.width, self.config.height);
+        self.cameras.insert(camera)
+    }
+
+    /// The main camera can't be removed, since there always has to be one.
+    pub fn remove_camera(&mut self, camera: CameraHandle) -> Result<()> {
+        self.cameras.try_get(camera)?;
+        if camera == self.main_camera {
+            return Err(Error::ResourceInUse("camera"));
+        }
+        self.cameras.remove(camera)?;
+        Ok(())
+    }
+
+    /// Finds the instance under a point on the screen, in pixels from the top left
+    /// corner (like `InputEvent::MouseLocation`). Where camera viewports overlap, the
+    /// camera drawn last wins, and only instances on its layers can be picked. Instances
+    /// of screen space pipelines (see `PipelineOptions`) never are.
+    pub fn pick(&self, x: f64, y: f64) -> Option<PickHit> {
+        let (x, y) = (x as f32, y as f32);
+        let (camera, (vx, vy, vw, vh)) = self.cameras.iter()
+            .map(|(_, camera)| camera)
+            .filter(|camera| camera.camera.render_target.is_none())
+            .map(|camera| (camera, camera.camera.viewport.to_pixels(self.config.width, self.config.height)))
+            .filter(|(_, (vx, vy, vw, vh))| {
+                x >= *vx as f32 && y >= *vy as f32 && x < (vx + vw) as f32 && y < (vy + vh) as f32
+            })
+            .last()?;
+        let ndc_x = (x - vx as f32) / vw as f32 * 2.0 - 1.0;
+        let ndc_y = 1.0 - (y - vy as f32) / vh as f32 * 2.0;
+        let ray = Ray::from_ndc(camera.camera.matrix(), ndc_x, ndc_y)?;
+
+        let mut nearest: Option<PickHit> = None;
+        for (mesh_handle, mesh) in self.loaded_meshes.iter() {
+            // screen space instances aren't in the world the ray goes through
+            if self.render_pipelines[mesh.render_pipeline].screen_space {
+                continue;
+            }
+            let bounds = match &mesh.bounds {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            for (key, instance) in mesh.instances.iter_keyed() {
+                if instance.layers & camera.camera.layers == 0 {
+                    continue;
+                }
+                let (center, radius) = instance.bounding_sphere(bounds);
+                if !ray.hits_sphere(center, radius) {
+                    continue;
+                }
+                let to_local = match instance.model_matrix().invert() {
+                    Some(matrix) => matrix,
+                    None => continue,
+                };
+                // the local ray's direction isn't normalized, so distances along it are still world distances
+                let local_ray = ray.transformed(&to_local);
+                let distance = mesh.indices.chunks_exact(3)
+                    .filter_map(|triangle| local_ray.hits_triangle(
+                        mesh.positions[triangle[0] as usize],
+                        mesh.positions[triangle[1] as usize],
+                        mesh.positions[triangle[2] as usize],
+                    ))
+                    .fold(None, |nearest: Option<f32>, d| Some(nearest.map_or(d, |n| n.min(d))));
+                if let Some(distance) = distance {
+                    if nearest.is_none_or(|hit| distance < hit.distance) {
+                        nearest = Some(PickHit {
+                            instance: InstanceHandle {
+                                mesh: mesh_handle,
+                                key,
+                            },
+                            position: ray.at(distance),
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+        nearest
     }
 
-    pub fn get_instance_mut(&mut self, instance_id: (usize, usize)) -> &mut ObjectInstance {
-        let (mesh, instance) = instance_id;
-        self.loaded_meshes[mesh].instances.get_mut(instance).unwrap()
+    /// Culling results of the last frame drawn.
+    pub fn culling_stats(&self) -> CullingStats {
+        self.culling_stats
     }
 
-    pub fn read_instance(&mut self, instance_id: (usize, usize)) -> &ObjectInstance {
-        let (mesh, instance) = instance_id;
-        let instance_obj = self.loaded_meshes[mesh].instances.get(instance).unwrap();
-        return instance_obj;
+    /// The camera every instance starts out with.
+    pub fn main_camera(&self) -> CameraHandle {
+        self.main_camera
     }
 
-    pub fn set_camera_transform(&mut self, new_position: Option<Point3<f32>>, new_target: Option<Point3<f32>>, new_up: Option<Vector3<f32>>) {
+    pub fn set_camera_transform(&mut self, camera: CameraHandle, new_position: Option<Point3<f32>>, new_target: Option<Point3<f32>>, new_up: Option<Vector3<f32>>) -> Result<()> {
+        let camera = &mut self.cameras.try_get_mut(camera)?.camera;
         if let Some(pos) = new_position {
-            self.camera.camera.pos = pos;
+            camera.pos = pos;
         }
         if let Some(target) = new_target {
-            self.camera.camera.target = target;
+            camera.target = target;
         }
         if let Some(up) = new_up {
-            self.camera.camera.up = up;
+            camera.up = up;
         }
+        Ok(())
     }
 
-    pub fn create_texture(&mut self, img: image::DynamicImage) -> usize {
-        let imgbuf = img.to_rgba();
-        let (width, height) = img.dimensions();
-        let tex_size = wgpu::Extent3d {
-            width,
-            height,
-            depth_or_array_layers: 1,
-        };
+    pub fn set_camera_projection(&mut self, camera: CameraHandle, projection: Projection) -> Result<()> {
+        self.cameras.try_get_mut(camera)?.camera.projection = projection;
+        Ok(())
+    }
 
-        let gpu_texture = self.device.create_texture(&wgpu::TextureDescriptor {
-            size: tex_size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            label: None,
-        });
-        self.queue.write_texture(
-            wgpu::ImageCopyTextureBase { 
-                texture: &gpu_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
+    pub fn camera_projection(&self, camera: CameraHandle) -> Result<Projection> {
+        Ok(self.cameras.try_get(camera)?.camera.projection)
+    }
+
+    /// Width divided by height of the camera's viewport. Follows the window as it's resized.
+    pub fn camera_aspect(&self, camera: CameraHandle) -> Result<f32> {
+        Ok(self.cameras.try_get(camera)?.camera.aspect)
+    }
+
+    /// The matrix taking world space to clip space, as the shaders get it.
+    pub fn camera_view_projection(&self, camera: CameraHandle) -> Result<Matrix4<f32>> {
+        Ok(self.cameras.try_get(camera)?.camera.matrix())
+    }
+
+    pub fn set_camera_viewport(&mut self, camera: CameraHandle, viewport: Viewport) -> Result<()> {
+        let (width, height) = self.camera_target_size(camera)?;
+        let camera = &mut self.cameras.try_get_mut(camera)?.camera;
+        camera.viewport = viewport;
+        camera.fit_to_target(width, height);
+        Ok(())
+    }
+
+    /// Makes the camera render into a texture made with `create_render_target`
+    /// instead of the frame, or back into the frame with `None`.
+    pub fn set_camera_target(&mut self, camera: CameraHandle, target: Option<TextureHandle>) -> Result<()> {
+        self.cameras.try_get(camera)?;
+        let (width, height) = match target {
+            Some(texture) => {
+                let render_target = self.textures.try_get(texture)?.render_target.as_ref()
+                    .ok_or(Error::InvalidHandle("render target"))?;
+                (render_target.width, render_target.height)
             },
-            &imgbuf,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * width),
-                rows_per_image: std::num::NonZeroU32::new(height),
+            None => (self.config.width, self.config.height),
+        };
+        let camera = &mut self.cameras[camera].camera;
+        camera.render_target = target;
+        camera.fit_to_target(width, height);
+        Ok(())
+    }
+
+    pub fn camera_target(&self, camera: CameraHandle) -> Result<Option<TextureHandle>> {
+        Ok(self.cameras.try_get(camera)?.camera.render_target)
+    }
+
+    // size of whatever the camera renders into
+    fn camera_target_size(&self, camera: CameraHandle) -> Result<(u32, u32)> {
+        Ok(match self.cameras.try_get(camera)?.camera.render_target {
+            Some(texture) => {
+                let render_target = self.textures[texture].render_target.as_ref()
+                    .expect("Camera targets a texture that isn't a render target");
+                (render_target.width, render_target.height)
             },
-            tex_size,
-        );
-        let view = gpu_texture.create_view(&wgpu::TextureViewDescriptor::default());
+            None => (self.config.width, self.config.height),
+        })
+    }
+
+    pub fn camera_viewport(&self, camera: CameraHandle) -> Result<Viewport> {
+        Ok(self.cameras.try_get(camera)?.camera.viewport)
+    }
+
+    /// The camera only draws instances whose `layers` share a bit with `layers`.
+    pub fn set_camera_layers(&mut self, camera: CameraHandle, layers: u32) -> Result<()> {
+        self.cameras.try_get_mut(camera)?.camera.layers = layers;
+        Ok(())
+    }
+
+    pub fn add_light<L: Into<Light>>(&mut self, light: L) -> Result<LightHandle> {
+        self.lights.add(light.into())
+    }
+
+    pub fn update_light<L: Into<Light>>(&mut self, light_id: LightHandle, light: L) -> Result<()> {
+        self.lights.update(light_id, light.into())
+    }
+
+    pub fn remove_light(&mut self, light_id: LightHandle) -> Result<()> {
+        self.lights.remove(light_id)
+    }
+
+    pub fn read_light(&self, light_id: LightHandle) -> Result<&Light> {
+        self.lights.get(light_id)
+    }
+
+    /// Light that reaches every surface equally, from no particular direction.
+    pub fn set_ambient_light(&mut self, color: [f32; 3]) {
+        self.lights.set_ambient(color);
+    }
+
+    /// Creates a texture from an image. Color textures can use `TextureOptions::default()`,
+    /// data like normal maps and metallic-roughness maps should use `TextureOptions::linear()`.
+    /// The texture is always 8-bit RGBA, images with more precision are clamped to 8 bits per channel.
+    pub fn create_texture(&mut self, img: image::DynamicImage, options: TextureOptions) -> Result<TextureHandle> {
+        let texture = create_gpu_texture(&self.device, &self.queue, &mut self.mipmaps, img, options)?;
+        Ok(self.textures.insert(texture))
+    }
+
+    /// Creates a texture from a compressed image (see `asset_loading::ktx2`). It stays
+    /// compressed on the GPU if the GPU supports its format. Otherwise it's decompressed
+    /// on the CPU and created like any other texture. Textures that stay compressed use
+    /// the mip levels in the file and never get mipmaps generated.
+    #[cfg(feature = "ktx2")]
+    pub fn create_compressed_texture(&mut self, image: &CompressedImage, options: TextureOptions) -> Result<TextureHandle> {
+        let texture = self.compressed_gpu_texture(image, options)?;
+        Ok(self.textures.insert(texture))
+    }
+
+    #[cfg(feature = "ktx2")]
+    fn compressed_gpu_texture(&mut self, image: &CompressedImage, options: TextureOptions) -> Result<Texture> {
+        let info = block_texture_format(image.format, options.srgb).describe();
+        let (block_width, block_height) = info.block_dimensions;
+        // wgpu only takes compressed textures made of whole blocks
+        let supported = self.device.features().contains(info.required_features)
+            && image.width.is_multiple_of(block_width as u32)
+            && image.height.is_multiple_of(block_height as u32);
+        if supported {
+            create_compressed_gpu_texture(&self.device, &self.queue, image, options)
+        } else {
+            let img = image::DynamicImage::ImageRgba8(image.to_rgba8());
+            create_gpu_texture(&self.device, &self.queue, &mut self.mipmaps, img, options)
+        }
+    }
+
+    /// Starts loading an image or KTX2 file on a background thread, and returns its texture
+    /// right away. The texture shows the magenta placeholder until the file is loaded and
+    /// uploaded, which happens during an update (see `load_state`). Only fails if the
+    /// placeholder can't be created.
+    pub fn load_texture(&mut self, path: &str, options: TextureOptions) -> Result<TextureHandle> {
+        let placeholder = create_gpu_texture(
+            &self.device,
+            &self.queue,
+            &mut self.mipmaps,
+            crate::asset_loading::images::default_texture(),
+            TextureOptions::pixelated(),
+        )?;
+        let texture = self.textures.insert(placeholder);
+        self.assets.load_texture(texture, path, options);
+        Ok(texture)
+    }
+
+    /// Starts loading a glTF file on a background thread. Once it's loaded (see `load_state`)
+    /// its meshes are bound with the pipeline, and `loaded_model` returns them.
+    pub fn load_model(&mut self, path: &str, render_pipeline: PipelineHandle) -> ModelHandle {
+        self.assets.load_model(path, render_pipeline)
+    }
+
+    /// The bound model, once it has finished loading
+    pub fn loaded_model(&self, model: ModelHandle) -> Option<&BoundModel> {
+        self.assets.models.get(model)?.bound.as_ref()
+    }
+
+    /// Places every object from a loaded model's scene graph where the file put it,
+    /// like `create_model_objects`. Fails with `Error::InvalidHandle` if the model
+    /// hasn't loaded (yet).
+    pub fn create_loaded_model_objects(&mut self, model: ModelHandle) -> Result<Vec<Vec<InstanceHandle>>> {
+        let loading = self.assets.models.try_get(model)?;
+        let bound = loading.bound.clone().ok_or(Error::InvalidHandle("loaded model"))?;
+        let objects = loading.objects.clone();
+        objects.into_iter()
+            .map(|object| self.create_model_instance(&bound, object.mesh, object.instance))
+            .collect()
+    }
+
+    /// How far along a texture or model loaded in the background is. `None` for anything
+    /// that wasn't loaded with `load_texture` or `load_model`.
+    pub fn load_state(&self, asset: impl Into<AssetId>) -> Option<&LoadState> {
+        self.assets.state(asset.into())
+    }
+
+    /// How many of the assets loaded in the background are done. Only counts the current
+    /// batch, everything started since the last time nothing was loading.
+    pub fn load_progress(&self) -> LoadProgress {
+        self.assets.progress()
+    }
+
+    /// Deletes a model started with `load_model`, loaded or not. Its meshes are deleted along
+    /// with their instances, and so are its materials and textures unless something else still
+    /// uses them.
+    pub fn delete_model(&mut self, model: ModelHandle) -> Result<()> {
+        let loading = self.assets.models.remove(model)?;
+        self.assets.forget(AssetId::Model(model));
+        if let Some(bound) = loading.bound {
+            self.delete_bound_model(bound);
+        }
+        Ok(())
+    }
+
+    // uploads whatever the workers finished decoding since the last update
+    fn receive_assets(&mut self) {
+        while let Some(decoded) = self.assets.try_receive() {
+            match decoded {
+                Decoded::Texture(texture, options, data) => {
+                    // deleted while it was loading
+                    if !self.textures.contains(texture) {
+                        self.assets.forget(AssetId::Texture(texture));
+                        continue;
+                    }
+                    let result = data.and_then(|data| self.replace_texture(texture, data, options));
+                    self.assets.finish(AssetId::Texture(texture), result);
+                }
+                Decoded::Model(model, gltf_model) => {
+                    // deleted while it was loading
+                    if !self.assets.models.contains(model) {
+                        self.assets.forget(AssetId::Model(model));
+                        continue;
+                    }
+                    let result = gltf_model.and_then(|gltf_model| {
+                        let render_pipeline = self.assets.models.try_get(model)?.render_pipeline;
+                        let bound = self.bind_model(&gltf_model, render_pipeline)?;
+                        let loading = &mut self.assets.models[model];
+                        loading.bound = Some(bound);
+                        loading.objects = gltf_model.objects;
+                        Ok(())
+                    });
+                    self.assets.finish(AssetId::Model(model), result);
+                }
+                Decoded::Failed(asset, error) => {
+                    // finish ignores assets that were deleted and forgotten in the meantime
+                    self.assets.finish(asset, Err(error));
+                }
+            }
+        }
+    }
+
+    // swaps a loaded texture in for its placeholder
+    fn replace_texture(&mut self, texture: TextureHandle, data: TextureData, options: TextureOptions) -> Result<()> {
+        let loaded = match data {
+            TextureData::Image(img) => create_gpu_texture(&self.device, &self.queue, &mut self.mipmaps, img, options)?,
+            #[cfg(feature = "ktx2")]
+            TextureData::Compressed(image) => self.compressed_gpu_texture(&image, options)?,
+        };
+        self.textures[texture] = loaded;
+        // bind groups hold on to the placeholder until they're recreated
+        for (_, loaded) in self.materials.iter_mut() {
+            if loaded.material.textures().any(|t| t == texture) {
+                *loaded = LoadedMaterial::new(
+                    &self.device,
+                    &self.material_bind_group_layout,
+                    loaded.material.clone(),
+                    &self.textures,
+                    &self.fallback_textures,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a texture that cameras can render into (see `set_camera_target`),
+    /// and that can be used in materials like any other texture. As a material's
+    /// `base_color_texture` it's bindable through the existing `texture_bind_group_layout`,
+    /// at bindings 0 and 1 of group 0 in every pipeline.
+    pub fn create_render_target(&mut self, width: u32, height: u32) -> Result<TextureHandle> {
+        if width == 0 || height == 0 {
+            return Err(Error::UnsupportedFormat("Render targets can't be empty".into()));
+        }
+        check_texture_size(&self.device, width, height)?;
+        // pipelines are built for the frame's format, so render targets have to share it
+        let (texture, depth) = validated(&self.device, || {
+            let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.config.format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+                label: Some("Render Target"),
+            });
+            (texture, DepthTexture::new(&self.device, width, height))
+        })?;
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = self.device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
-            address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
         });
 
-        let bind_group = self.device.create_bind_group(
-            &wgpu::BindGroupDescriptor {
-                layout: &self.texture_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&sampler),
-                    }
-                ],
-                label: None,
-            }
-        );
-        self.textures.insert(Texture { bind_group })
+        Ok(self.textures.insert(Texture {
+            texture,
+            view,
+            sampler,
+            render_target: Some(RenderTarget {
+                depth,
+                width,
+                height,
+            }),
+        }))
+    }
+
+    pub fn create_material(&mut self, material: Material) -> Result<MaterialHandle> {
+        let loaded = LoadedMaterial::new(
+            &self.device,
+            &self.material_bind_group_layout,
+            material,
+            &self.textures,
+            &self.fallback_textures,
+        )?;
+        Ok(self.materials.insert(loaded))
+    }
+
+    /// Replaces a material. Every mesh using it picks up the change.
+    pub fn update_material(&mut self, material_id: MaterialHandle, material: Material) -> Result<()> {
+        self.materials.try_get(material_id)?;
+        self.materials[material_id] = LoadedMaterial::new(
+            &self.device,
+            &self.material_bind_group_layout,
+            material,
+            &self.textures,
+            &self.fallback_textures,
+        )?;
+        Ok(())
+    }
+
+    pub fn read_material(&self, material_id: MaterialHandle) -> Result<&Material> {
+        Ok(&self.materials.try_get(material_id)?.material)
+    }
+
+    /// The layout of group 0 in every pipeline, which is what a material binds. Bindings 0
+    /// and 1 are a texture and its sampler (the base color), so a shader that only samples
+    /// one texture, like the gui shader, declares just those two. The rest are the material's
+    /// uniform at 2, then texture and sampler pairs for metallic-roughness (3, 4), the
+    /// normal map (5, 6), occlusion (7, 8) and emission (9, 10).
+    pub fn texture_bind_group_layout(&self) -> &BindGroupLayout {
+        &self.material_bind_group_layout
+    }
+
+    /// The material meshes get when there's nothing better: a magenta checkerboard.
+    pub fn default_material(&self) -> MaterialHandle {
+        self.default_material
     }
 
-    pub fn create_glyph_brush(&mut self, font_path: &str) -> usize {
-        let file = std::fs::File::open(font_path).unwrap();
+    pub fn create_glyph_brush(&mut self, font_path: &str) -> Result<GlyphBrushHandle> {
+        let file = std::fs::File::open(font_path)?;
         let mut file_reader = std::io::BufReader::new(file);
         let mut file_buffer = Vec::new();
-        file_reader.read_to_end(&mut file_buffer).unwrap();
-        let font = wgpu_glyph::ab_glyph::FontArc::try_from_vec(file_buffer).unwrap();
-        let brush = wgpu_glyph::GlyphBrushBuilder::using_font(font).build(&mut self.device, self.config.format);
-        self.glyph_brushes.insert(brush)
+        file_reader.read_to_end(&mut file_buffer)?;
+        let font = wgpu_glyph::ab_glyph::FontArc::try_from_vec(file_buffer)
+            .map_err(|e| Error::Decode(format!("{}: {}", font_path, e)))?;
+        let brush = wgpu_glyph::GlyphBrushBuilder::using_font(font).build(&self.device, self.config.format);
+        Ok(self.glyph_brushes.insert(brush))
     }
 
-    pub fn create_text_box(&mut self, text_instance: TextInstance) -> usize {
-        self.text_instances.insert(text_instance)
+    pub fn create_text_box(&mut self, text_instance: TextInstance) -> Result<TextBoxHandle> {
+        self.glyph_brushes.try_get(text_instance.brush)?;
+        Ok(self.text_instances.insert(text_instance))
     }
 
-    pub fn delete_text_box(&mut self, text_instance: usize) {
-        self.text_instances.remove(text_instance);
+    pub fn delete_text_box(&mut self, text_instance: TextBoxHandle) -> Result<()> {
+        self.text_instances.remove(text_instance)?;
+        Ok(())
     }
 
-    pub fn get_textbox_mut(&mut self, text_instance: usize) -> &mut TextInstance {
-        self.text_instances.get_mut(text_instance).unwrap()
+    /// Text boxes whose `brush` is changed to one that doesn't exist are skipped when drawing.
+    pub fn get_textbox_mut(&mut self, text_instance: TextBoxHandle) -> Result<&mut TextInstance> {
+        self.text_instances.try_get_mut(text_instance)
     }
 
-    // TODO implement delete_shader_program
-    pub fn delete_shader_program() {}
+    /// Fails with `Error::ResourceInUse` while any bound mesh still uses the pipeline.
+    pub fn delete_shader_program(&mut self, render_pipeline: PipelineHandle) -> Result<()> {
+        self.render_pipelines.try_get(render_pipeline)?;
+        if self.loaded_meshes.iter().any(|(_, m)| m.render_pipeline == render_pipeline) {
+            return Err(Error::ResourceInUse("render pipeline"));
+        }
+        self.render_pipelines.remove(render_pipeline)?;
+        Ok(())
+    }
 
-    // TODO implement delete_mesh
-    pub fn delete_mesh() {}
+    /// Frees the mesh's buffers. All of its instances are removed along with it.
+    pub fn delete_mesh(&mut self, mesh: MeshHandle) -> Result<()> {
+        self.loaded_meshes.remove(mesh)?;
+        self.caster_bounds_dirty = true;
+        Ok(())
+    }
 
-    // TODO implement delete_texture
-    pub fn delete_texture() {}
+    /// Fails with `Error::ResourceInUse` while any material or camera still uses the texture.
+    /// The engine's builtin textures can't be deleted either.
+    pub fn delete_texture(&mut self, texture: TextureHandle) -> Result<()> {
+        self.textures.try_get(texture)?;
+        if texture == self.default_texture
+            || texture == self.fallback_textures.white
+            || texture == self.fallback_textures.flat_normal
+            || self.materials.iter().any(|(_, m)| m.material.textures().any(|t| t == texture))
+            || self.cameras.iter().any(|(_, c)| c.camera.render_target == Some(texture)) {
+            return Err(Error::ResourceInUse("texture"));
+        }
+        self.textures.remove(texture)?;
+        self.assets.forget(AssetId::Texture(texture));
+        Ok(())
+    }
+
+    /// Fails with `Error::ResourceInUse` while any bound mesh still uses the material.
+    /// The default material can't be deleted.
+    pub fn delete_material(&mut self, material: MaterialHandle) -> Result<()> {
+        self.materials.try_get(material)?;
+        if material == self.default_material
+            || self.loaded_meshes.iter().any(|(_, m)| m.material == material) {
+            return Err(Error::ResourceInUse("material"));
+        }
+        self.materials.remove(material)?;
+        Ok(())
+    }
 
     pub(crate) fn update(&mut self) {
-        self.camera.update(&mut self.queue);
+        self.receive_assets();
+        for (_, camera) in self.cameras.iter_mut() {
+            camera.update(&mut self.queue);
+        }
         for (_, mesh) in self.loaded_meshes.iter_mut() {
-            mesh.update_instance_buffer(&mut self.device);
+            self.caster_bounds_dirty |= mesh.instances.take_changed();
         }
+        if self.caster_bounds_dirty {
+            self.caster_bounds = self.shadow_caster_bounds();
+            self.caster_bounds_dirty = false;
+        }
+        // the cameras move all the time, so what they see isn't worth caching
+        let view_bounds = enclosing_sphere(self.cameras.iter()
+            .filter_map(|(_, camera)| camera.camera.view_bounds(DIRECTIONAL_SHADOW_DISTANCE)));
+        let shadow_bounds = self.caster_bounds.map(|casters| ShadowBounds::new(casters, view_bounds));
+        self.lights.write_buffer(&mut self.queue, shadow_bounds.as_ref());
+        for (_, mesh) in self.loaded_meshes.iter_mut() {
+            mesh.instances.update_buffer(&self.device, &self.queue);
+        }
+    }
+
+    // a sphere around every shadow caster's bounding sphere, for fitting directional shadow maps
+    fn shadow_caster_bounds(&self) -> Option<Sphere> {
+        enclosing_sphere(self.loaded_meshes.iter()
+            .filter(|(_, mesh)| !self.render_pipelines[mesh.render_pipeline].screen_space)
+            .filter_map(|(_, mesh)| mesh.bounds.as_ref().map(|bounds| (mesh, bounds)))
+            .flat_map(|(mesh, bounds)| mesh.instances.iter()
+                .filter(|instance| instance.cast_shadows)
+                .map(move |instance| instance.bounding_sphere(bounds))))
     }
 
     // TODO this function cannot take mut self, it must be &self
     pub(crate) fn draw(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        match &self.target {
+            FrameTarget::Surface(surface) => {
+                let output = surface.get_current_texture()?;
+                let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                if let Err(e) = self.render_frame(&view) {
+                    log::error!("{}", e);
+                }
+                output.present();
+            },
+            FrameTarget::Offscreen(texture) => {
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                if let Err(e) = self.render_frame(&view) {
+                    log::error!("{}", e);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Renders a frame and copies it back to the CPU. Works on both headless and
+    /// windowed instances; a windowed instance renders into a scratch texture
+    /// instead of its surface, so nothing gets presented.
+    pub fn draw_to_image(&mut self) -> Result<image::RgbaImage> {
+        self.update();
+        let scratch = match &self.target {
+            FrameTarget::Surface(_) => Some(create_offscreen_texture(&self.device, &self.config)),
+            FrameTarget::Offscreen(_) => None,
+        };
+        let view = self.capture_texture(&scratch).create_view(&wgpu::TextureViewDescriptor::default());
+        self.render_frame(&view)?;
+        self.read_texture(self.capture_texture(&scratch))
+    }
+
+    fn capture_texture<'a>(&'a self, scratch: &'a Option<wgpu::Texture>) -> &'a wgpu::Texture {
+        match (scratch, &self.target) {
+            (Some(texture), _) | (None, FrameTarget::Offscreen(texture)) => texture,
+            (None, FrameTarget::Surface(_)) => unreachable!("windowed instances always capture into a scratch texture"),
+        }
+    }
+
+    fn read_texture(&self, texture: &wgpu::Texture) -> Result<image::RgbaImage> {
+        let (width, height) = (self.config.width, self.config.height);
+        // rows in the readback buffer have to be padded to a multiple of 256 bytes
+        let unpadded_bytes_per_row = 4 * width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Render Encoder"),
+            label: Some("Readback Encoder"),
         });
-        { // scope block to release the borrow of encoder when done
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        block_on(mapping)?;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+
+        // surfaces usually prefer BGRA, but the image crate wants RGBA
+        if let TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb = self.config.format {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Ok(image::RgbaImage::from_raw(width, height, pixels).expect("Readback buffer has the wrong size"))
+    }
+
+    fn draw_shadow_maps(&self, encoder: &mut CommandEncoder) {
+        let shadow_maps = &self.lights.shadow_maps;
+        for layer in 0..shadow_maps.in_use {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &shadow_maps.layer_views[layer],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
                         store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
+                    }),
+                    stencil_ops: None,
+                }),
             });
-
-            // TODO as noted in the documentation, .iter() on a Slab is SLOW
-            // but we can make it not slow by regularly comapcting the Slab.
-            // so TODO: implement packing/defraging of loaded_meshes at regular intervals,
-            // or find a different data structure to use.
+            render_pass.set_pipeline(&shadow_maps.pipeline);
+            render_pass.set_bind_group(0, &shadow_maps.pass_bind_groups[layer], &[]);
             for (_, m) in self.loaded_meshes.iter() {
-                render_pass.set_pipeline(&self.render_pipelines[m.render_pipeline]);
-                if let Some(id) = m.texture_id {
-                    render_pass.set_bind_group(0, &self.textures[id].bind_group, &[]);
-                } else {
-                    render_pass.set_bind_group(0, &self.textures[0].bind_group, &[]);
+                // screen space instances aren't anywhere in the world to cast a shadow from
+                if self.render_pipelines[m.render_pipeline].screen_space {
+                    continue;
+                }
+                let casters = m.shadow_caster_ranges();
+                if casters.is_empty() {
+                    continue;
                 }
-                render_pass.set_bind_group(1, &self.camera.bind_group, &[]);
                 render_pass.set_vertex_buffer(0, m.vertex_buffer.slice(..));
-                render_pass.set_vertex_buffer(1, m.instance_buffer.slice(..));
+                render_pass.set_vertex_buffer(1, m.instances.buffer().slice(..));
                 render_pass.set_index_buffer(m.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                render_pass.draw_indexed(0..m.num_indices, 0, 0..(m.instances.len() as u32));
+                for range in casters {
+                    render_pass.draw_indexed(0..m.num_indices, 0, range);
+                }
+            }
+        }
+    }
+
+    /// Draws what the camera sees into its viewport on the target. Returns false if
+    /// the viewport is empty, then no pass is recorded and nothing gets cleared either.
+    fn draw_camera(
+        &self,
+        encoder: &mut CommandEncoder,
+        camera: &RenderableCamera,
+        target: PassTarget,
+        clear: bool,
+        stats: &mut CullingStats,
+    ) -> bool {
+        let (x, y, width, height) = camera.camera.viewport.to_pixels(target.width, target.height);
+        if width == 0 || height == 0 {
+            return false;
+        }
+        let frustum = camera.camera.frustum();
+        // depth is cleared for every camera so they don't hide each other's geometry
+        let color_load = if clear {
+            wgpu::LoadOp::Clear(CLEAR_COLOR)
+        } else {
+            wgpu::LoadOp::Load
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target.color,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: color_load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: target.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0.0, 1.0);
+        render_pass.set_scissor_rect(x, y, width, height);
+
+        // instances are packed (see instances.rs), but meshes still live in a Slab,
+        // and .iter() on a Slab is SLOW once it has lots of holes.
+        // TODO: pack loaded_meshes the same way if that ever shows up in profiles
+        for (_, m) in self.loaded_meshes.iter() {
+            let pipeline = &self.render_pipelines[m.render_pipeline];
+            // screen space instances don't go through the camera, so its frustum says nothing about them
+            let cull_against = if pipeline.screen_space { None } else { Some(&frustum) };
+            let visible = m.visible_ranges(camera.camera.layers, cull_against, stats);
+            if visible.is_empty() {
+                continue;
+            }
+            // a texture can't be sampled while it's being rendered into
+            let material = &self.materials[m.material];
+            if let Some(target) = camera.camera.render_target {
+                if material.material.textures().any(|t| t == target) {
+                    continue;
+                }
+            }
+            render_pass.set_pipeline(&pipeline.pipeline);
+            render_pass.set_bind_group(0, &material.bind_group, &[]);
+            render_pass.set_bind_group(1, &camera.bind_group, &[]);
+            render_pass.set_bind_group(2, &self.lights.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, m.vertex_buffer.slice(..));
+            render_pass.set_vertex_buffer(1, m.instances.buffer().slice(..));
+            render_pass.set_index_buffer(m.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            for range in visible {
+                render_pass.draw_indexed(0..m.num_indices, 0, range);
+            }
+        }
+        true
+    }
+
+    // an empty pass, for targets no camera drew into this frame
+    fn clear_target(encoder: &mut CommandEncoder, target: PassTarget) {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Clear Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target.color,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(CLEAR_COLOR),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: target.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+    }
+
+    fn pass_target<'a>(&'a self, render_target: Option<TextureHandle>, view: &'a TextureView) -> PassTarget<'a> {
+        match render_target {
+            Some(texture) => {
+                let texture = &self.textures[texture];
+                let render_target = texture.render_target.as_ref()
+                    .expect("Camera targets a texture that isn't a render target");
+                PassTarget {
+                    color: &texture.view,
+                    depth: &render_target.depth.view,
+                    width: render_target.width,
+                    height: render_target.height,
+                }
+            },
+            None => PassTarget {
+                color: view,
+                depth: &self.depth_texture.view,
+                width: self.config.width,
+                height: self.config.height,
+            },
+        }
+    }
+
+    /// Draws every camera into its target, and clears every target that no camera
+    /// drew into. `targets` are cleared even if none of the cameras use them.
+    fn draw_cameras(
+        &self,
+        encoder: &mut CommandEncoder,
+        cameras: &[&RenderableCamera],
+        mut targets: Vec<Option<TextureHandle>>,
+        view: &TextureView,
+        stats: &mut CullingStats,
+    ) {
+        let mut cleared = HashSet::new();
+        for camera in cameras {
+            let render_target = camera.camera.render_target;
+            if !targets.contains(&render_target) {
+                targets.push(render_target);
+            }
+            // the first camera that draws into a target clears it, the others draw over what's already there
+            let clear = !cleared.contains(&render_target);
+            let target = self.pass_target(render_target, view);
+            if self.draw_camera(encoder, camera, target, clear, stats) {
+                cleared.insert(render_target);
+            }
+        }
+        for render_target in targets {
+            if !cleared.contains(&render_target) {
+                Self::clear_target(encoder, self.pass_target(render_target, view));
             }
         }
-        for (_, txt) in self.text_instances.iter() {
-            let brush = self.glyph_brushes.get_mut(txt.brush).unwrap();
+    }
+
+    /// The frame is submitted even when its text fails to draw; that error is returned afterwards.
+    fn render_frame(&mut self, view: &TextureView) -> Result<()> {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+        self.draw_shadow_maps(&mut encoder);
+
+        // cameras rendering into textures go first, so the frame can show what they saw this frame
+        let (offscreen, onscreen): (Vec<_>, Vec<_>) = self.cameras.iter()
+            .map(|(_, camera)| camera)
+            .partition(|camera| camera.camera.render_target.is_some());
+        let mut stats = CullingStats::default();
+        self.draw_cameras(&mut encoder, &offscreen, Vec::new(), view, &mut stats);
+        // the frame itself is always cleared, even without any camera drawing into it
+        self.draw_cameras(&mut encoder, &onscreen, vec![None], view, &mut stats);
+        self.culling_stats = stats;
+        // text failing to draw shouldn't throw away the rest of the frame
+        let text = self.draw_text(&mut encoder, view);
+        self.text_staging_belt.finish();
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        self.local_spawner.spawn(self.text_staging_belt.recall()).unwrap();
+        self.local_pool.run_until_stalled();
+        text
+    }
+
+    fn draw_text(&mut self, encoder: &mut CommandEncoder, view: &TextureView) -> Result<()> {
+        let mut used_brushes = Vec::new();
+        for (handle, txt) in self.text_instances.iter() {
+            let brush = match self.glyph_brushes.try_get_mut(txt.brush) {
+                Ok(brush) => brush,
+                Err(_) => {
+                    log::warn!("text box {:?} uses a glyph brush that doesn't exist, skipping it", handle);
+                    continue;
+                }
+            };
             brush.queue_custom_layout(
                 wgpu_glyph::Section {
                     screen_position: txt.position,
@@ -444,52 +1345,268 @@ impl RenderingInstance {
                     .h_align(wgpu_glyph::HorizontalAlign::Center)
                     .v_align(wgpu_glyph::VerticalAlign::Top),
             );
-            brush.draw_queued(
+            if !used_brushes.contains(&txt.brush) {
+                used_brushes.push(txt.brush);
+            }
+        }
+        // one draw per brush, with everything queued on it
+        for handle in used_brushes {
+            self.glyph_brushes.try_get_mut(handle)?.draw_queued(
                 &self.device,
                 &mut self.text_staging_belt,
-                &mut encoder,
-                &view,
-                640,
-                480,
-            ).unwrap();
+                encoder,
+                view,
+                self.size.width,
+                self.size.height,
+            ).map_err(Error::Gpu)?;
         }
-        
-        self.text_staging_belt.finish();
+        Ok(())
+    }
+}
 
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+// wgpu's default error handler panics, so sizes past the device's limits are
+// caught before they get to it
+fn check_texture_size(device: &Device, width: u32, height: u32) -> Result<()> {
+    let max = device.limits().max_texture_dimension_2d;
+    if width > max || height > max {
+        return Err(Error::Gpu(format!("A {}x{} texture is larger than the GPU allows ({}x{})", width, height, max, max)));
+    }
+    Ok(())
+}
 
-        self.local_spawner.spawn(self.text_staging_belt.recall()).unwrap();
-        self.local_pool.run_until_stalled();
+// runs `create` with wgpu's validation errors returned instead of panicking
+fn validated<T>(device: &Device, create: impl FnOnce() -> T) -> Result<T> {
+    device.push_error_scope(ErrorFilter::Validation);
+    let created = create();
+    match block_on(device.pop_error_scope()) {
+        Some(error) => Err(error.into()),
+        None => Ok(created),
+    }
+}
 
-        Ok(())
+fn create_gpu_texture(device: &Device, queue: &Queue, mipmaps: &mut MipmapGenerator, img: image::DynamicImage, options: TextureOptions) -> Result<Texture> {
+    // every texture is 8-bit RGBA, 16-bit channels get scaled down here
+    let imgbuf = img.to_rgba8();
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Err(Error::UnsupportedFormat("Textures can't be empty".into()));
+    }
+    check_texture_size(device, width, height)?;
+    let tex_size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+
+    let format = options.format();
+    let mip_level_count = options.mip_level_count(width, height);
+    let mut usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+    if mip_level_count > 1 {
+        // the mip levels are rendered into
+        usage |= wgpu::TextureUsages::RENDER_ATTACHMENT;
+    }
+
+    let (gpu_texture, view, sampler) = validated(device, || {
+        let gpu_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: tex_size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+            label: None,
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTextureBase { 
+                texture: &gpu_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &imgbuf,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(4 * width),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+            tex_size,
+        );
+        mipmaps.generate(device, queue, &gpu_texture, format, mip_level_count);
+        let view = gpu_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&options.sampler_descriptor());
+        (gpu_texture, view, sampler)
+    })?;
+
+    Ok(Texture {
+        texture: gpu_texture,
+        view,
+        sampler,
+        render_target: None,
+    })
+}
+
+#[cfg(feature = "ktx2")]
+fn create_compressed_gpu_texture(device: &Device, queue: &Queue, image: &CompressedImage, options: TextureOptions) -> Result<Texture> {
+    let format = image.format;
+    let texture_format = block_texture_format(format, options.srgb);
+    let tex_size = wgpu::Extent3d {
+        width: image.width,
+        height: image.height,
+        depth_or_array_layers: 1,
+    };
+    let levels = &image.levels[..image.levels.len().min(tex_size.max_mips() as usize)];
+    check_texture_size(device, image.width, image.height)?;
+
+    let (gpu_texture, view, sampler) = validated(device, || {
+        let gpu_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: tex_size,
+            mip_level_count: levels.len() as u32,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture_format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: None,
+        });
+        for (level, data) in levels.iter().enumerate() {
+            let (blocks_x, blocks_y) = image.level_blocks(level);
+            queue.write_texture(
+                wgpu::ImageCopyTextureBase {
+                    texture: &gpu_texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(blocks_x * format.bytes_per_block()),
+                    rows_per_image: std::num::NonZeroU32::new(blocks_y),
+                },
+                // small mip levels still take up a whole block
+                tex_size.mip_level_size(level as u32, false).physical_size(texture_format),
+            );
+        }
+        let view = gpu_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&options.sampler_descriptor());
+        (gpu_texture, view, sampler)
+    })?;
+
+    Ok(Texture {
+        texture: gpu_texture,
+        view,
+        sampler,
+        render_target: None,
+    })
+}
+
+fn create_offscreen_texture(device: &Device, config: &SurfaceConfiguration) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        label: Some("Offscreen Texture"),
+    })
+}
+
+/// How much frustum culling saved in the last frame, summed over every camera.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CullingStats {
+    /// Instances on the camera's layers, which had to be checked against its frustum.
+    /// Screen space instances count too, but are never culled.
+    pub tested: usize,
+    /// Instances that were outside the frustum and skipped
+    pub culled: usize,
+}
+
+impl CullingStats {
+    pub fn drawn(&self) -> usize {
+        self.tested - self.culled
+    }
+}
+
+/// Per-pipeline settings for `create_render_pipeline_with_options`.
+pub struct PipelineOptions {
+    /// Whether geometry drawn with this pipeline writes to the depth buffer.
+    /// Depth testing is always on, but transparent geometry usually shouldn't
+    /// hide what's drawn behind it afterwards.
+    pub depth_write: bool,
+    /// Whether the shader places instances on screen itself, without the camera (like
+    /// the default GUI shader does). Instances drawn with it are never frustum culled.
+    pub screen_space: bool,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            depth_write: true,
+            screen_space: false,
+        }
     }
 }
 
+struct LoadedPipeline {
+    pipeline: RenderPipeline,
+    screen_space: bool,
+}
+
 /// A mesh with loaded vertex/index buffers
 struct LoadedMesh {
     vertex_buffer: Buffer,
     index_buffer: Buffer,
     num_indices: u32,
-    render_pipeline: usize,
-    // TODO instances should be stored in their own place that references the mesh,
-    // but then we'll have to write some nice code to efficiently construct the
-    // instances range every frame.
-    instances: Slab<ObjectInstance>,
-    instance_buffer: Buffer,
-    texture_id: Option<usize>,
+    // None for meshes without vertices, which have nothing to cull
+    bounds: Option<BoundingSphere>,
+    // copies of the mesh's geometry for picking
+    positions: Vec<Point3<f32>>,
+    indices: Vec<u32>,
+    render_pipeline: PipelineHandle,
+    instances: InstanceStore,
+    material: MaterialHandle,
 }
 
+#[derive(Clone)]
 pub struct ObjectInstance {
     pub position: Vector3<f32>,
     pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+    /// Bit mask of the layers the instance is on. Cameras only draw instances
+    /// that share a layer with them. Everything starts out on layer 0.
+    pub layers: u32,
+    /// Multiplied with the instance's color by the default shader. White by default.
+    /// Shaders get it at `[[location(12)]]`.
+    pub tint: [f32; 4],
+    /// Free for custom shaders to use, at `[[location(13)]]`. The default shader ignores it.
+    pub data: [f32; 4],
+    /// Whether the instance is drawn into shadow maps. Instances of screen space
+    /// pipelines (see `PipelineOptions`) never are.
+    pub cast_shadows: bool,
+    /// Whether shadows show up on the instance. Shaders get it as 0.0 or 1.0 at `[[location(14)]]`.
+    pub receive_shadows: bool,
 }
 
+// scales below this count as zero when splitting a matrix into an ObjectInstance
+const MIN_SCALE: f32 = 1e-6;
+// how far a matrix may be from its decomposition, relative to its scale, before it counts as sheared
+const DECOMPOSITION_TOLERANCE: f32 = 1e-4;
+
 impl ObjectInstance {
     pub fn from_position(x: f32, y: f32, z: f32) -> Self {
         Self {
             position: Vector3::<f32>::new(x, y, z),
             rotation: Quaternion::<f32>::from_axis_angle(Vector3::unit_z(), Deg(0.0)),
+            scale: Vector3::<f32>::new(1.0, 1.0, 1.0),
+            layers: 1,
+            tint: [1.0, 1.0, 1.0, 1.0],
+            data: [0.0; 4],
+            cast_shadows: true,
+            receive_shadows: true,
         }
     }
 
@@ -498,44 +1615,212 @@ impl ObjectInstance {
         self
     }
 
-    fn as_raw(&self) -> RawObjectInstance {
-        let mat4 = Matrix4::from_translation(self.position) * Matrix4::from(self.rotation);
-        RawObjectInstance { matrix: mat4.into() }
+    pub fn with_scale(mut self, x: f32, y: f32, z: f32) -> Self {
+        self.scale = Vector3::<f32>::new(x, y, z);
+        self
+    }
+
+    pub fn with_uniform_scale(self, scale: f32) -> Self {
+        self.with_scale(scale, scale, scale)
+    }
+
+    pub fn with_layers(mut self, layers: u32) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    pub fn with_tint(mut self, tint: [f32; 4]) -> Self {
+        self.tint = tint;
+        self
+    }
+
+    pub fn with_data(mut self, data: [f32; 4]) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn with_shadows(mut self, cast: bool, receive: bool) -> Self {
+        self.cast_shadows = cast;
+        self.receive_shadows = receive;
+        self
+    }
+
+    /// Splits an affine transform matrix into position, rotation and scale.
+    /// Shear can't be represented, so matrices with shear (e.g. a rotated child
+    /// of a non-uniformly scaled parent) come out approximated. Use `try_from_matrix`
+    /// to find out when that happens.
+    pub fn from_matrix(matrix: Matrix4<f32>) -> Self {
+        let columns = [matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate()];
+        let mut scale = Vector3::<f32>::new(columns[0].magnitude(), columns[1].magnitude(), columns[2].magnitude());
+        // axes scaled to nothing have no direction, so they're rebuilt from the others
+        let axes = [0, 1, 2].map(|i| (scale[i] > MIN_SCALE).then(|| columns[i] / scale[i]));
+        let mut basis = match axes {
+            [Some(x), Some(y), Some(z)] => Matrix3::from_cols(x, y, z),
+            [None, Some(y), Some(z)] => Matrix3::from_cols(y.cross(z).normalize(), y, z),
+            [Some(x), None, Some(z)] => Matrix3::from_cols(x, z.cross(x).normalize(), z),
+            [Some(x), Some(y), None] => Matrix3::from_cols(x, y, x.cross(y).normalize()),
+            // squashed onto a line or a point, so at most one direction is left to keep
+            _ => match axes.iter().position(Option::is_some) {
+                Some(i) => Matrix3::from(Quaternion::from_arc(Matrix3::identity()[i], axes[i].unwrap(), None)),
+                None => Matrix3::identity(),
+            },
+        };
+        // a mirrored transform can't be a pure rotation, so push the flip into the scale
+        if basis.determinant() < 0.0 {
+            scale.x = -scale.x;
+            basis.x = -basis.x;
+        }
+
+        Self {
+            position: matrix.w.truncate(),
+            rotation: Quaternion::from(basis).normalize(),
+            scale,
+            layers: 1,
+            tint: [1.0, 1.0, 1.0, 1.0],
+            data: [0.0; 4],
+            cast_shadows: true,
+            receive_shadows: true,
+        }
+    }
+
+    /// Like `from_matrix`, but fails with `Error::UnsupportedFormat` if the matrix has
+    /// shear (or isn't affine), instead of approximating it.
+    pub fn try_from_matrix(matrix: Matrix4<f32>) -> Result<Self> {
+        let instance = Self::from_matrix(matrix);
+        let recomposed = instance.model_matrix();
+        let size = instance.scale.x.abs().max(instance.scale.y.abs()).max(instance.scale.z.abs()).max(1.0);
+        let error = (0..4)
+            .flat_map(|column| (0..4).map(move |row| (column, row)))
+            .map(|(column, row)| (recomposed[column][row] - matrix[column][row]).abs())
+            .fold(0.0, f32::max);
+        if error > size * DECOMPOSITION_TOLERANCE {
+            return Err(Error::UnsupportedFormat("Instance transforms can't have shear".into()));
+        }
+        Ok(instance)
+    }
+
+    // the mesh's bounding sphere moved into world space. non-uniform scaling
+    // stretches the sphere into an ellipsoid, so the radius grows by the largest scale
+    fn bounding_sphere(&self, bounds: &BoundingSphere) -> (Point3<f32>, f32) {
+        let offset = self.rotation.rotate_vector(bounds.center.to_vec().mul_element_wise(self.scale));
+        let max_scale = self.scale.x.abs().max(self.scale.y.abs()).max(self.scale.z.abs());
+        (Point3::from_vec(self.position + offset), bounds.radius * max_scale)
+    }
+
+    pub fn model_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.position)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    pub(in crate::rendering) fn as_raw(&self) -> RawObjectInstance {
+        let model = self.model_matrix();
+        // normals have to go through the inverse transpose of the model matrix,
+        // otherwise non-uniform scaling skews them. a zero scale makes the matrix
+        // singular, but then there's nothing visible to light anyway.
+        let upper_left = Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        let normal_matrix = upper_left.invert()
+            .map(|inverse| inverse.transpose())
+            .unwrap_or_else(Matrix3::identity);
+        RawObjectInstance {
+            matrix: model.into(),
+            normal_matrix: normal_matrix.into(),
+            tint: self.tint,
+            data: self.data,
+            receive_shadows: if self.receive_shadows { 1.0 } else { 0.0 },
+        }
     }
 }
 
 impl LoadedMesh {
-    fn add_instance(&mut self, instance: ObjectInstance) -> usize {
+    fn add_instance(&mut self, instance: ObjectInstance) -> InstanceKey {
         self.instances.insert(instance)
     }
 
-    fn remove_instance(&mut self, instance: usize) {
-        self.instances.remove(instance);
+    fn remove_instance(&mut self, instance: InstanceKey) -> Result<()> {
+        self.instances.remove(instance)?;
+        Ok(())
     }
 
-    pub fn update_instance_buffer(&mut self, device: &mut Device) {
-        let data = self.instances.iter().map(|(_, inst)| inst.as_raw().matrix).collect::<Vec<_>>();
-        self.instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&data),
-                usage: wgpu::BufferUsages::VERTEX,
+    fn instance_mut(&mut self, instance: InstanceKey) -> Result<&mut ObjectInstance> {
+        self.instances.get_mut(instance)
+    }
+
+    /// Runs of consecutive instances (as laid out in the instance buffer) that are
+    /// on any of `layers` and at least partly inside the frustum, if there is one
+    fn visible_ranges(&self, layers: u32, frustum: Option<&Frustum>, stats: &mut CullingStats) -> Vec<std::ops::Range<u32>> {
+        self.ranges_where(|instance| {
+            if instance.layers & layers == 0 {
+                return false;
             }
-        );
+            stats.tested += 1;
+            if let (Some(bounds), Some(frustum)) = (&self.bounds, frustum) {
+                let (center, radius) = instance.bounding_sphere(bounds);
+                if !frustum.intersects_sphere(center, radius) {
+                    stats.culled += 1;
+                    return false;
+                }
+            }
+            true
+        })
+    }
+
+    /// Runs of consecutive instances that are drawn into shadow maps
+    fn shadow_caster_ranges(&self) -> Vec<std::ops::Range<u32>> {
+        self.ranges_where(|instance| instance.cast_shadows)
+    }
+
+    fn ranges_where(&self, mut keep: impl FnMut(&ObjectInstance) -> bool) -> Vec<std::ops::Range<u32>> {
+        let mut ranges: Vec<std::ops::Range<u32>> = Vec::new();
+        for (i, instance) in self.instances.iter().enumerate() {
+            if !keep(instance) {
+                continue;
+            }
+            let i = i as u32;
+            match ranges.last_mut() {
+                Some(last) if last.end == i => last.end = i + 1,
+                _ => ranges.push(i..i + 1),
+            }
+        }
+        ranges
     }
 }
 
-struct Texture {
-    bind_group: BindGroup,
+pub(in crate::rendering) struct Texture {
+    #[allow(dead_code)] // kept alive alongside its view
+    texture: wgpu::Texture,
+    pub view: TextureView,
+    pub sampler: Sampler,
+    // set for textures made with create_render_target
+    render_target: Option<RenderTarget>,
+}
+
+// what a single camera's render pass draws into
+struct PassTarget<'a> {
+    color: &'a TextureView,
+    depth: &'a TextureView,
+    width: u32,
+    height: u32,
+}
+
+struct RenderTarget {
+    depth: DepthTexture,
+    width: u32,
+    height: u32,
 }
 
 #[repr(C)]
-struct RawObjectInstance {
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(in crate::rendering) struct RawObjectInstance {
     matrix: [[f32;4];4],
+    normal_matrix: [[f32;3];3],
+    tint: [f32; 4],
+    data: [f32; 4],
+    receive_shadows: f32,
 }
 
 impl RawObjectInstance {
-    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+    pub(in crate::rendering) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
         wgpu::VertexBufferLayout {
             array_stride: mem::size_of::<RawObjectInstance>() as wgpu::BufferAddress,
@@ -561,6 +1846,36 @@ impl RawObjectInstance {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 25]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 29]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 33]>() as wgpu::BufferAddress,
+                    shader_location: 14,
+                    format: wgpu::VertexFormat::Float32,
+                },
             ],
         }
     }
@@ -572,11 +1887,11 @@ pub struct TextInstance {
     pub color: [f32; 4],
     pub scale: f32,
     pub text: String,
-    pub brush: usize,
+    pub brush: GlyphBrushHandle,
 }
 
 impl Vertex {
-    fn desc<'a>() -> VertexBufferLayout<'a> {
+    pub(in crate::rendering) fn desc<'a>() -> VertexBufferLayout<'a> {
         VertexBufferLayout {
             array_stride: size_of::<Vertex>() as BufferAddress,
             step_mode: VertexStepMode::Vertex,
@@ -599,4 +1914,50 @@ impl Vertex {
             ]
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Matrix4<f32>, b: Matrix4<f32>) {
+        let a: [[f32; 4]; 4] = a.into();
+        let b: [[f32; 4]; 4] = b.into();
+        for (a, b) in a.iter().flatten().zip(b.iter().flatten()) {
+            assert!((a - b).abs() < 1e-4, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn is_finite(instance: &ObjectInstance) -> bool {
+        let rotation = instance.rotation;
+        [rotation.s, rotation.v.x, rotation.v.y, rotation.v.z].iter().all(|v| v.is_finite())
+            && instance.scale.x.is_finite() && instance.scale.y.is_finite() && instance.scale.z.is_finite()
+    }
+
+    #[test]
+    fn matrices_without_shear_round_trip() {
+        let matrix = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0))
+            * Matrix4::from_axis_angle(Vector3::new(1.0, 1.0, 0.0).normalize(), Deg(40.0))
+            * Matrix4::from_nonuniform_scale(2.0, 0.5, -3.0);
+        let instance = ObjectInstance::try_from_matrix(matrix).unwrap();
+        assert_close(instance.model_matrix(), matrix);
+    }
+
+    #[test]
+    fn zero_scale_gives_a_valid_rotation() {
+        let rotation = Matrix4::from_angle_y(Deg(30.0));
+        for scale in [(0.0, 1.0, 1.0), (1.0, 0.0, 2.0), (0.0, 0.0, 2.0), (0.0, 0.0, 0.0)] {
+            let matrix = rotation * Matrix4::from_nonuniform_scale(scale.0, scale.1, scale.2);
+            let instance = ObjectInstance::try_from_matrix(matrix).unwrap();
+            assert!(is_finite(&instance), "{:?} gave {:?} {:?}", scale, instance.rotation, instance.scale);
+            assert_close(instance.model_matrix(), matrix);
+        }
+    }
+
+    #[test]
+    fn shear_is_reported() {
+        // a rotated child of a non-uniformly scaled parent
+        let matrix = Matrix4::from_nonuniform_scale(1.0, 3.0, 1.0) * Matrix4::from_angle_z(Deg(45.0));
+        assert!(ObjectInstance::try_from_matrix(matrix).is_err());
+        assert!(is_finite(&ObjectInstance::from_matrix(matrix)));
+    }
+}
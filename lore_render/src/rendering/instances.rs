@@ -0,0 +1,330 @@
+/*!
+ * the instances of one mesh, along with the GPU buffer they're drawn from.
+ *
+ * instances are packed densely, so the buffer never has holes and every frame
+ * only walks the live ones. handles don't point at a position directly, they go
+ * through a slot table, which lets an instance move when another one is removed:
+ * the last instance is swapped into the hole, so removal only ever moves one
+ * instance and every other instance keeps its position in the buffer.
+ */
+
+use crate::{
+    error::Result,
+    handle::{HandleSlab, InstanceKey},
+    rendering::engine::{ObjectInstance, RawObjectInstance},
+};
+use std::{mem::size_of, ops::Range};
+
+/// How many instances a mesh's instance buffer has room for at first
+const INITIAL_INSTANCE_CAPACITY: usize = 16;
+
+pub(in crate::rendering) struct InstanceStore {
+    instances: PackedInstances,
+    // kept between frames, only reallocated when it has to grow or can shrink a lot
+    buffer: wgpu::Buffer,
+    capacity: usize,
+}
+
+impl InstanceStore {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            instances: PackedInstances::new(),
+            buffer: create_instance_buffer(device, INITIAL_INSTANCE_CAPACITY),
+            capacity: INITIAL_INSTANCE_CAPACITY,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn insert(&mut self, instance: ObjectInstance) -> InstanceKey {
+        self.instances.insert(instance)
+    }
+
+    /// Swaps the last instance into the removed one's place.
+    pub fn remove(&mut self, key: InstanceKey) -> Result<ObjectInstance> {
+        self.instances.remove(key)
+    }
+
+    pub fn get(&self, key: InstanceKey) -> Result<&ObjectInstance> {
+        self.instances.get(key)
+    }
+
+    /// The instance is uploaded again at the next update, whether it was changed or not.
+    pub fn get_mut(&mut self, key: InstanceKey) -> Result<&mut ObjectInstance> {
+        self.instances.get_mut(key)
+    }
+
+    /// Whether any instance was added, removed or changed since the last call.
+    /// Unlike the dirty positions, this isn't reset by uploading.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.instances.changed)
+    }
+
+    /// Instances in buffer order
+    pub fn iter(&self) -> impl Iterator<Item = &ObjectInstance> {
+        self.instances.instances.iter()
+    }
+
+    /// Instances in buffer order, along with their keys
+    pub fn iter_keyed(&self) -> impl Iterator<Item = (InstanceKey, &ObjectInstance)> {
+        self.instances.keys.iter().copied().zip(self.instances.instances.iter())
+    }
+
+    /// Uploads instances that changed since the last call. Nothing is allocated
+    /// unless the buffer has to grow, or gets compacted after most instances were removed.
+    pub fn update_buffer(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let dirty_ranges = self.instances.take_dirty_ranges();
+        let len = self.instances.len();
+        let grow = len > self.capacity;
+        let compact = self.capacity > INITIAL_INSTANCE_CAPACITY && len * 4 < self.capacity;
+        if grow || compact {
+            self.capacity = if grow {
+                len.max(self.capacity * 2)
+            } else {
+                (len * 2).max(INITIAL_INSTANCE_CAPACITY)
+            };
+            self.buffer = create_instance_buffer(device, self.capacity);
+            self.instances.shrink_to(self.capacity);
+            // a new buffer starts out empty
+            write_instances(queue, &self.buffer, 0, &self.instances.instances);
+        } else {
+            for range in dirty_ranges {
+                write_instances(queue, &self.buffer, range.start, &self.instances.instances[range]);
+            }
+        }
+    }
+}
+
+// the bookkeeping side of an instance store, without the buffer
+struct PackedInstances {
+    // packed, in the same order as in the buffer
+    instances: Vec<ObjectInstance>,
+    // key of the instance at every position, to fix up slots when instances move
+    keys: Vec<InstanceKey>,
+    // position of every instance in `instances`
+    slots: HandleSlab<InstanceKey, usize>,
+    // positions changed since the last upload, `dirty` has a flag per position
+    // so each one is only listed once. flags past the end are kept until the next
+    // upload, so a position that's removed and refilled isn't listed twice
+    dirty: Vec<bool>,
+    dirty_positions: Vec<usize>,
+    // set whenever an instance is added, removed or changed, see take_changed
+    changed: bool,
+}
+
+impl PackedInstances {
+    fn new() -> Self {
+        Self {
+            instances: Vec::new(),
+            keys: Vec::new(),
+            slots: HandleSlab::new(),
+            dirty: Vec::new(),
+            dirty_positions: Vec::new(),
+            changed: false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    // where the instance currently sits in the instance buffer
+    fn position(&self, key: InstanceKey) -> Result<usize> {
+        self.slots.try_get(key).copied()
+    }
+
+    fn insert(&mut self, instance: ObjectInstance) -> InstanceKey {
+        let position = self.instances.len();
+        let key = self.slots.insert(position);
+        self.instances.push(instance);
+        self.keys.push(key);
+        if self.dirty.len() == position {
+            self.dirty.push(false);
+        }
+        self.mark_dirty(position);
+        self.changed = true;
+        key
+    }
+
+    fn remove(&mut self, key: InstanceKey) -> Result<ObjectInstance> {
+        let position = self.slots.remove(key)?;
+        let last = self.instances.len() - 1;
+        let removed = self.instances.swap_remove(position);
+        self.keys.swap_remove(position);
+        self.changed = true;
+        if position != last {
+            self.slots[self.keys[position]] = position;
+            self.mark_dirty(position);
+        }
+        Ok(removed)
+    }
+
+    fn get(&self, key: InstanceKey) -> Result<&ObjectInstance> {
+        Ok(&self.instances[self.position(key)?])
+    }
+
+    fn get_mut(&mut self, key: InstanceKey) -> Result<&mut ObjectInstance> {
+        let position = self.position(key)?;
+        self.mark_dirty(position);
+        self.changed = true;
+        Ok(&mut self.instances[position])
+    }
+
+    fn mark_dirty(&mut self, position: usize) {
+        if !self.dirty[position] {
+            self.dirty[position] = true;
+            self.dirty_positions.push(position);
+        }
+    }
+
+    // the positions changed since the last call, merged into runs of consecutive
+    // instances so each run goes up in a single write
+    fn take_dirty_ranges(&mut self) -> Vec<Range<usize>> {
+        let len = self.instances.len();
+        self.dirty_positions.sort_unstable();
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        // positions past the end were removed after they were marked
+        for &position in self.dirty_positions.iter().take_while(|&&position| position < len) {
+            match ranges.last_mut() {
+                Some(range) if range.end == position => range.end += 1,
+                _ => ranges.push(position..position + 1),
+            }
+        }
+        for position in self.dirty_positions.drain(..) {
+            self.dirty[position] = false;
+        }
+        self.dirty.truncate(len);
+        ranges
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        self.instances.shrink_to(capacity);
+        self.keys.shrink_to(capacity);
+        self.dirty.shrink_to(capacity);
+    }
+}
+
+fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Instance Buffer"),
+        size: (capacity * size_of::<RawObjectInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+// writes instances into the buffer, starting at the given instance index
+fn write_instances(queue: &wgpu::Queue, buffer: &wgpu::Buffer, first: usize, instances: &[ObjectInstance]) {
+    if !instances.is_empty() {
+        let data = instances.iter().map(ObjectInstance::as_raw).collect::<Vec<_>>();
+        let offset = (first * size_of::<RawObjectInstance>()) as wgpu::BufferAddress;
+        queue.write_buffer(buffer, offset, bytemuck::cast_slice(&data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // instances tagged by their x position
+    fn packed(count: usize) -> (PackedInstances, Vec<InstanceKey>) {
+        let mut instances = PackedInstances::new();
+        let keys = (0..count).map(|i| instances.insert(ObjectInstance::from_position(i as f32, 0.0, 0.0))).collect();
+        (instances, keys)
+    }
+
+    fn tag(instances: &PackedInstances, key: InstanceKey) -> f32 {
+        instances.get(key).unwrap().position.x
+    }
+
+    #[test]
+    fn inserted_instances_are_uploaded_in_one_range() {
+        let (mut instances, _) = packed(4);
+        assert_eq!(instances.take_dirty_ranges(), vec![0..4]);
+        assert_eq!(instances.take_dirty_ranges(), vec![]);
+    }
+
+    #[test]
+    fn removing_from_the_middle_moves_the_last_instance_into_the_hole() {
+        let (mut instances, keys) = packed(4);
+        instances.take_dirty_ranges();
+        assert_eq!(tag(&instances, keys[1]), 1.0);
+        assert_eq!(instances.remove(keys[1]).unwrap().position.x, 1.0);
+        assert_eq!(instances.len(), 3);
+        assert_eq!(instances.position(keys[3]).unwrap(), 1);
+        for &key in &[keys[0], keys[2], keys[3]] {
+            assert_eq!(tag(&instances, key), keys.iter().position(|&k| k == key).unwrap() as f32);
+        }
+        assert!(matches!(instances.get(keys[1]), Err(crate::Error::InvalidHandle(_))));
+        // only the moved instance goes up again
+        assert_eq!(instances.take_dirty_ranges(), vec![1..2]);
+    }
+
+    #[test]
+    fn removing_the_last_instance_moves_nothing() {
+        let (mut instances, keys) = packed(3);
+        instances.take_dirty_ranges();
+        instances.remove(keys[2]).unwrap();
+        assert_eq!(instances.take_dirty_ranges(), vec![]);
+        assert_eq!(instances.position(keys[0]).unwrap(), 0);
+        assert_eq!(instances.position(keys[1]).unwrap(), 1);
+    }
+
+    #[test]
+    fn removed_dirty_positions_are_not_uploaded() {
+        let (mut instances, keys) = packed(4);
+        instances.remove(keys[3]).unwrap();
+        instances.remove(keys[2]).unwrap();
+        assert_eq!(instances.take_dirty_ranges(), vec![0..2]);
+    }
+
+    #[test]
+    fn readding_after_removal_reuses_the_position_once() {
+        let (mut instances, keys) = packed(3);
+        instances.remove(keys[2]).unwrap();
+        let readded = instances.insert(ObjectInstance::from_position(7.0, 0.0, 0.0));
+        assert_ne!(readded, keys[2]);
+        assert_eq!(instances.position(readded).unwrap(), 2);
+        assert_eq!(tag(&instances, readded), 7.0);
+        assert!(instances.get(keys[2]).is_err());
+        assert_eq!(instances.take_dirty_ranges(), vec![0..3]);
+        assert_eq!(instances.dirty_positions.len(), 0);
+
+        // and the same after an upload, with the removed instance still marked dirty
+        instances.get_mut(readded).unwrap();
+        instances.remove(readded).unwrap();
+        let again = instances.insert(ObjectInstance::from_position(8.0, 0.0, 0.0));
+        assert_eq!(instances.dirty_positions, vec![2]);
+        assert_eq!(instances.take_dirty_ranges(), vec![2..3]);
+        assert_eq!(tag(&instances, again), 8.0);
+    }
+
+    #[test]
+    fn changes_split_into_separate_ranges() {
+        let (mut instances, keys) = packed(6);
+        instances.take_dirty_ranges();
+        instances.get_mut(keys[4]).unwrap();
+        instances.get_mut(keys[0]).unwrap();
+        instances.get_mut(keys[1]).unwrap();
+        instances.get_mut(keys[0]).unwrap();
+        assert_eq!(instances.take_dirty_ranges(), vec![0..2, 4..5]);
+    }
+
+    #[test]
+    fn every_mutation_counts_as_a_change() {
+        let (mut instances, keys) = packed(2);
+        assert!(std::mem::take(&mut instances.changed));
+        instances.get(keys[0]).unwrap();
+        assert!(!instances.changed);
+        instances.get_mut(keys[0]).unwrap();
+        assert!(std::mem::take(&mut instances.changed));
+        instances.remove(keys[1]).unwrap();
+        assert!(std::mem::take(&mut instances.changed));
+        // uploading doesn't reset it
+        instances.get_mut(keys[0]).unwrap();
+        instances.take_dirty_ranges();
+        assert!(instances.changed);
+    }
+}
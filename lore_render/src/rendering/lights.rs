@@ -0,0 +1,339 @@
+/*!
+ * lights that the default shader shades with. all lights live in one uniform
+ * buffer that gets rewritten whenever a light changes, so there's a fixed cap
+ * on how many can exist at once (`MAX_LIGHTS`).
+ *
+ * shaders made with `create_render_pipeline` can read the lights too:
+ * they're bound at group 2, binding 0, laid out like `LightsUniform` below.
+ * the shadow maps are bound next to them, see shadows.rs.
+ */
+
+use crate::{
+    error::{Error, Result},
+    handle::{HandleSlab, LightHandle},
+    rendering::shadows::{ShadowMaps, ShadowBounds, MAX_SHADOW_MAPS, directional_shadow_matrix, spot_shadow_matrix},
+};
+use cgmath::{Deg, Point3, Vector3, Matrix4, InnerSpace, Angle};
+use bytemuck::Zeroable;
+use wgpu::util::DeviceExt;
+
+/// The most lights that can exist at the same time. Has to match the default shader.
+pub const MAX_LIGHTS: usize = 16;
+
+/// A light infinitely far away, shining in one direction everywhere (like the sun).
+#[derive(Clone, Debug)]
+pub struct DirectionalLight {
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// Only the first `MAX_SHADOW_MAPS` shadow-casting lights actually get shadows
+    pub cast_shadows: bool,
+}
+
+/// A light shining in every direction from a point, fading out towards `range`.
+#[derive(Clone, Debug)]
+pub struct PointLight {
+    pub position: Point3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+}
+
+/// A point light that only shines in a cone. Full brightness inside `inner_angle`,
+/// fading to nothing at `outer_angle`. Both angles are measured from `direction`.
+#[derive(Clone, Debug)]
+pub struct SpotLight {
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_angle: Deg<f32>,
+    pub outer_angle: Deg<f32>,
+    /// Only the first `MAX_SHADOW_MAPS` shadow-casting lights actually get shadows
+    pub cast_shadows: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum Light {
+    Directional(DirectionalLight),
+    Point(PointLight),
+    Spot(SpotLight),
+}
+
+impl From<DirectionalLight> for Light {
+    fn from(light: DirectionalLight) -> Self {
+        Light::Directional(light)
+    }
+}
+
+impl From<PointLight> for Light {
+    fn from(light: PointLight) -> Self {
+        Light::Point(light)
+    }
+}
+
+impl From<SpotLight> for Light {
+    fn from(light: SpotLight) -> Self {
+        Light::Spot(light)
+    }
+}
+
+impl Light {
+    fn cast_shadows(&self) -> bool {
+        match self {
+            Light::Directional(light) => light.cast_shadows,
+            Light::Point(_) => false,
+            Light::Spot(light) => light.cast_shadows,
+        }
+    }
+
+    // matrix into the light's shadow map. directional lights need to know where
+    // the shadow casters are, and without any there's nothing to cast a shadow
+    fn shadow_matrix(&self, shadow_bounds: Option<&ShadowBounds>) -> Option<Matrix4<f32>> {
+        match self {
+            Light::Directional(light) => shadow_bounds
+                .map(|bounds| directional_shadow_matrix(light.direction, bounds)),
+            Light::Point(_) => None,
+            Light::Spot(light) => Some(spot_shadow_matrix(light.position, light.direction, light.outer_angle, light.range)),
+        }
+    }
+
+    fn as_raw(&self) -> RawLight {
+        match self {
+            Light::Directional(light) => RawLight {
+                position: [0.0, 0.0, 0.0, LIGHT_KIND_DIRECTIONAL],
+                direction: light.direction.normalize().extend(0.0).into(),
+                color: scaled_color(light.color, light.intensity),
+                cone: [0.0, 0.0, NO_SHADOW_MAP, 0.0],
+            },
+            Light::Point(light) => RawLight {
+                position: [light.position.x, light.position.y, light.position.z, LIGHT_KIND_POINT],
+                direction: [0.0, 0.0, 0.0, light.range],
+                color: scaled_color(light.color, light.intensity),
+                cone: [0.0, 0.0, NO_SHADOW_MAP, 0.0],
+            },
+            Light::Spot(light) => {
+                // the shader fades between the two cosines, so they can't be equal or swapped
+                let outer_angle = Deg(light.outer_angle.0.max(light.inner_angle.0 + MIN_CONE_FADE.0));
+                RawLight {
+                    position: [light.position.x, light.position.y, light.position.z, LIGHT_KIND_SPOT],
+                    direction: light.direction.normalize().extend(light.range).into(),
+                    color: scaled_color(light.color, light.intensity),
+                    cone: [light.inner_angle.cos(), outer_angle.cos(), NO_SHADOW_MAP, 0.0],
+                }
+            },
+        }
+    }
+}
+
+fn scaled_color(color: [f32; 3], intensity: f32) -> [f32; 4] {
+    [color[0] * intensity, color[1] * intensity, color[2] * intensity, 1.0]
+}
+
+// stored in the w component of RawLight::position
+const LIGHT_KIND_DIRECTIONAL: f32 = 0.0;
+const LIGHT_KIND_POINT: f32 = 1.0;
+const LIGHT_KIND_SPOT: f32 = 2.0;
+
+// stored in the z component of RawLight::cone for lights without a shadow map
+const NO_SHADOW_MAP: f32 = -1.0;
+
+// narrowest fade between a spot light's inner and outer angle
+const MIN_CONE_FADE: Deg<f32> = Deg(0.5);
+
+pub(in crate::rendering) struct RenderableLights {
+    lights: HandleSlab<LightHandle, Light>,
+    ambient: [f32; 3],
+    // set when the lights change, so the buffer is only rewritten when it has to be
+    dirty: bool,
+    // for every light with a shadow map: its position in `lights` and its matrix
+    shadows: Vec<(usize, Matrix4<f32>)>,
+    pub shadow_maps: ShadowMaps,
+    buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl RenderableLights {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("lights_buffer"),
+                contents: bytemuck::bytes_of(&LightsUniform::zeroed()),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+            ],
+            label: Some("lights_bind_group_layout"),
+        });
+        let shadow_maps = ShadowMaps::new(device);
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&shadow_maps.array_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&shadow_maps.sampler),
+                },
+            ],
+            label: Some("lights_bind_group"),
+        });
+
+        Self {
+            lights: HandleSlab::new(),
+            // same floor the default shader used to hard-code
+            ambient: [0.05, 0.05, 0.05],
+            dirty: true,
+            shadows: Vec::new(),
+            shadow_maps,
+            buffer,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    pub fn add(&mut self, light: Light) -> Result<LightHandle> {
+        if self.lights.len() >= MAX_LIGHTS {
+            return Err(Error::LimitReached("lights"));
+        }
+        self.dirty = true;
+        Ok(self.lights.insert(light))
+    }
+
+    pub fn update(&mut self, handle: LightHandle, light: Light) -> Result<()> {
+        *self.lights.try_get_mut(handle)? = light;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, handle: LightHandle) -> Result<()> {
+        self.lights.remove(handle)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn get(&self, handle: LightHandle) -> Result<&Light> {
+        self.lights.try_get(handle)
+    }
+
+    pub fn set_ambient(&mut self, color: [f32; 3]) {
+        self.ambient = color;
+        self.dirty = true;
+    }
+
+    /// Directional shadow maps are fit to `shadow_bounds`, which is `None` when
+    /// nothing casts a shadow.
+    pub fn write_buffer(&mut self, queue: &mut wgpu::Queue, shadow_bounds: Option<&ShadowBounds>) {
+        // directional shadows follow the casters, so they can change without any light changing
+        let shadows = self.lights.iter()
+            .enumerate()
+            .filter(|(_, (_, light))| light.cast_shadows())
+            .filter_map(|(i, (_, light))| light.shadow_matrix(shadow_bounds).map(|matrix| (i, matrix)))
+            .take(MAX_SHADOW_MAPS)
+            .collect::<Vec<_>>();
+        if shadows != self.shadows {
+            self.shadows = shadows;
+            self.dirty = true;
+        }
+        if !self.dirty {
+            return;
+        }
+        let mut uniform = LightsUniform::zeroed();
+        uniform.ambient = [self.ambient[0], self.ambient[1], self.ambient[2], 1.0];
+        for (i, (_, light)) in self.lights.iter().enumerate() {
+            uniform.lights[i] = light.as_raw();
+        }
+        for (shadow_map, (i, matrix)) in self.shadows.iter().enumerate() {
+            uniform.lights[*i].cone[2] = shadow_map as f32;
+            uniform.shadow_matrices[shadow_map] = (*matrix).into();
+        }
+        uniform.count = self.lights.len() as u32;
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&uniform));
+        let matrices = self.shadows.iter().map(|(_, matrix)| *matrix).collect::<Vec<_>>();
+        self.shadow_maps.write_matrices(queue, &matrices);
+        self.dirty = false;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct RawLight {
+    position: [f32; 4], // w is the kind of light
+    direction: [f32; 4], // w is the range
+    color: [f32; 4], // already multiplied by intensity
+    cone: [f32; 4], // cosines of the inner and outer angle, then the shadow map index or -1
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsUniform {
+    ambient: [f32; 4],
+    count: u32,
+    _padding: [u32; 3], // arrays in uniforms are 16-byte aligned
+    lights: [RawLight; MAX_LIGHTS],
+    shadow_matrices: [[[f32; 4]; 4]; MAX_SHADOW_MAPS],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spot(inner_angle: f32, outer_angle: f32) -> Light {
+        Light::Spot(SpotLight {
+            position: Point3::new(0.0, 0.0, 0.0),
+            direction: -Vector3::unit_y(),
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            range: 10.0,
+            inner_angle: Deg(inner_angle),
+            outer_angle: Deg(outer_angle),
+            cast_shadows: false,
+        })
+    }
+
+    #[test]
+    fn spot_cones_always_have_a_fade() {
+        for (inner, outer) in [(30.0, 30.0), (40.0, 20.0), (0.0, 0.0)] {
+            let raw = spot(inner, outer).as_raw();
+            assert!(raw.cone[0] > raw.cone[1], "{} {} gave {:?}", inner, outer, raw.cone);
+        }
+        let raw = spot(20.0, 40.0).as_raw();
+        assert_eq!(raw.cone[1], Deg(40.0f32).cos());
+    }
+}
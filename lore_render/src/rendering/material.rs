@@ -0,0 +1,242 @@
+/*!
+ * PBR metallic-roughness materials, matching what glTF describes.
+ *
+ * a material's factors go into a small uniform buffer, and together with its
+ * textures that makes up the bind group at group 0 of every pipeline:
+ *
+ * ```text
+ *     binding 0, 1: base color texture and sampler
+ *     binding 2: MaterialUniform
+ *     binding 3, 4: metallic-roughness texture and sampler
+ *     binding 5, 6: normal map and sampler
+ *     binding 7, 8: occlusion texture and sampler
+ *     binding 9, 10: emissive texture and sampler
+ * ```
+ *
+ * the base color stays at bindings 0 and 1, so a shader that only samples one
+ * texture (like the gui shader) works with any material.
+ */
+
+use crate::{
+    error::Result,
+    handle::{HandleSlab, TextureHandle},
+    rendering::engine::Texture,
+};
+use wgpu::util::DeviceExt;
+
+#[derive(Clone, Debug)]
+pub struct Material {
+    /// Multiplied with the base color texture
+    pub base_color_factor: [f32; 4],
+    /// Untextured materials use a plain white texture
+    pub base_color_texture: Option<TextureHandle>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    /// Roughness in the green channel, metalness in the blue channel (like glTF).
+    /// Should be created with `TextureOptions::linear()`.
+    pub metallic_roughness_texture: Option<TextureHandle>,
+    /// Tangent space normal map. Should be created with `TextureOptions::linear()`.
+    pub normal_texture: Option<TextureHandle>,
+    pub normal_scale: f32,
+    /// Ambient occlusion in the red channel. Should be created with `TextureOptions::linear()`.
+    pub occlusion_texture: Option<TextureHandle>,
+    pub occlusion_strength: f32,
+    pub emissive_factor: [f32; 3],
+    pub emissive_texture: Option<TextureHandle>,
+}
+
+impl Default for Material {
+    /// A plain white, fully rough, non-metallic material.
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic_factor: 0.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            emissive_factor: [0.0, 0.0, 0.0],
+            emissive_texture: None,
+        }
+    }
+}
+
+impl Material {
+    /// Every texture the material refers to
+    pub(in crate::rendering) fn textures(&self) -> impl Iterator<Item = TextureHandle> {
+        vec![
+            self.base_color_texture,
+            self.metallic_roughness_texture,
+            self.normal_texture,
+            self.occlusion_texture,
+            self.emissive_texture,
+        ].into_iter().flatten()
+    }
+
+    fn as_uniform(&self) -> MaterialUniform {
+        let [r, g, b] = self.emissive_factor;
+        MaterialUniform {
+            base_color_factor: self.base_color_factor,
+            emissive_factor: [r, g, b, 1.0],
+            metallic_factor: self.metallic_factor,
+            roughness_factor: self.roughness_factor,
+            normal_scale: self.normal_scale,
+            occlusion_strength: self.occlusion_strength,
+        }
+    }
+}
+
+/// Textures that fill the slots a material leaves empty.
+pub(in crate::rendering) struct FallbackTextures {
+    pub white: TextureHandle,
+    pub flat_normal: TextureHandle,
+}
+
+/// A material with its uniform buffer and bind group.
+pub(in crate::rendering) struct LoadedMaterial {
+    pub material: Material,
+    #[allow(dead_code)] // buffer must be stored since bind_group references it
+    buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl LoadedMaterial {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        material: Material,
+        textures: &HandleSlab<TextureHandle, Texture>,
+        fallbacks: &FallbackTextures,
+    ) -> Result<Self> {
+        let base_color = textures.try_get(material.base_color_texture.unwrap_or(fallbacks.white))?;
+        let metallic_roughness = textures.try_get(material.metallic_roughness_texture.unwrap_or(fallbacks.white))?;
+        let normal = textures.try_get(material.normal_texture.unwrap_or(fallbacks.flat_normal))?;
+        let occlusion = textures.try_get(material.occlusion_texture.unwrap_or(fallbacks.white))?;
+        let emissive = textures.try_get(material.emissive_texture.unwrap_or(fallbacks.white))?;
+
+        let buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("material_buffer"),
+                contents: bytemuck::cast_slice(&[material.as_uniform()]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            }
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&base_color.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&base_color.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&metallic_roughness.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(&normal.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Sampler(&occlusion.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::TextureView(&emissive.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::Sampler(&emissive.sampler),
+                },
+            ],
+            label: Some("material_bind_group"),
+        });
+
+        Ok(Self {
+            material,
+            buffer,
+            bind_group,
+        })
+    }
+}
+
+pub(in crate::rendering) fn create_material_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    };
+    let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        // This should match the filterable field of the
+        // corresponding Texture entry above.
+        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+        count: None,
+    };
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            texture_entry(0),
+            sampler_entry(1),
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            texture_entry(3),
+            sampler_entry(4),
+            texture_entry(5),
+            sampler_entry(6),
+            texture_entry(7),
+            sampler_entry(8),
+            texture_entry(9),
+            sampler_entry(10),
+        ],
+        label: Some("material_bind_group_layout"),
+    })
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    base_color_factor: [f32; 4],
+    emissive_factor: [f32; 4], // w unused
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+}
@@ -1,5 +1,14 @@
 pub mod engine;
+pub mod assets;
 pub mod camera;
+pub mod model;
+pub mod lights;
+pub mod material;
+pub mod picking;
+pub mod shadows;
+pub mod textures;
+mod depth;
+mod instances;
 
 use engine::*;
 use winit::{
@@ -26,12 +35,12 @@ pub fn run<T: 'static>(
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().with_inner_size(winit::dpi::PhysicalSize::new(640, 480)).build(&event_loop).unwrap();
     // This instance will be given to the event_loop, will not be kept
-    let mut rendering_instance = block_on(RenderingInstance::new(&window));
+    let mut rendering_instance = block_on(RenderingInstance::new(&window)).expect("Failed to create rendering instance");
 
     let mut state = setup(&mut rendering_instance);
 
     // Run the main program loop
-    event_loop.run(move |event, window_target, control_flow| {
+    event_loop.run(move |event, _, control_flow| {
         match event {
             Event::WindowEvent {
                 ref event,
@@ -0,0 +1,137 @@
+/*!
+ * binding whole glTF models at once. a glTF mesh is made of several primitives
+ * that each have their own material, so one mesh in the file turns into several
+ * bound meshes here, and placing the mesh means placing every one of them.
+ */
+
+use crate::{
+    asset_loading::meshes::{GltfModel, GltfImage},
+    error::{Error, Result},
+    handle::{MeshHandle, TextureHandle, PipelineHandle, InstanceHandle, MaterialHandle},
+    rendering::{
+        engine::{RenderingInstance, ObjectInstance},
+        material::Material,
+        textures::TextureOptions,
+    },
+};
+use std::collections::HashMap;
+
+/// A `GltfModel` whose meshes, images and materials have been uploaded.
+#[derive(Clone, Debug)]
+pub struct BoundModel {
+    /// For every mesh in the model, the bound meshes of its primitives
+    pub meshes: Vec<Vec<MeshHandle>>,
+    /// Materials, lined up with the model's materials
+    pub materials: Vec<MaterialHandle>,
+    /// Every texture created for the model. An image used both as color and as
+    /// data (like a normal map) is uploaded twice, once in sRGB and once linear.
+    pub textures: Vec<TextureHandle>,
+}
+
+impl RenderingInstance {
+    /// Uploads the model's images, materials and meshes. If any of them fails, the
+    /// ones created before it are deleted again before the error is returned.
+    pub fn bind_model(&mut self, model: &GltfModel, render_pipeline: PipelineHandle) -> Result<BoundModel> {
+        let mut bound = BoundModel {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            textures: Vec::new(),
+        };
+        match self.bind_model_parts(model, render_pipeline, &mut bound) {
+            Ok(()) => Ok(bound),
+            Err(e) => {
+                self.delete_bound_model(bound);
+                Err(e)
+            }
+        }
+    }
+
+    // adds everything to `bound` as soon as it's created, so it can be cleaned up after a failure
+    fn bind_model_parts(&mut self, model: &GltfModel, render_pipeline: PipelineHandle, bound: &mut BoundModel) -> Result<()> {
+        // images are uploaded on first use, since how they're stored depends on what they're used for
+        let mut uploaded = HashMap::<(usize, bool), TextureHandle>::new();
+        let textures = &mut bound.textures;
+        let mut texture = |engine: &mut Self, image: Option<usize>, srgb: bool| -> Result<Option<TextureHandle>> {
+            let image = match image {
+                Some(image) => image,
+                None => return Ok(None),
+            };
+            if let Some(handle) = uploaded.get(&(image, srgb)) {
+                return Ok(Some(*handle));
+            }
+            let img = model.images.get(image)
+                .ok_or_else(|| Error::Decode(format!("glTF material refers to missing image {}", image)))?;
+            let options = if srgb {
+                TextureOptions::default()
+            } else {
+                TextureOptions::linear()
+            };
+            let handle = match img {
+                GltfImage::Decoded(img) => engine.create_texture(img.clone(), options)?,
+                #[cfg(feature = "ktx2")]
+                GltfImage::Compressed(img) => engine.create_compressed_texture(img, options)?,
+            };
+            uploaded.insert((image, srgb), handle);
+            textures.push(handle);
+            Ok(Some(handle))
+        };
+
+        for gltf_material in &model.materials {
+            let material = Material {
+                base_color_factor: gltf_material.base_color_factor,
+                base_color_texture: texture(self, gltf_material.base_color_texture, true)?,
+                metallic_factor: gltf_material.metallic_factor,
+                roughness_factor: gltf_material.roughness_factor,
+                metallic_roughness_texture: texture(self, gltf_material.metallic_roughness_texture, false)?,
+                normal_texture: texture(self, gltf_material.normal_texture, false)?,
+                normal_scale: gltf_material.normal_scale,
+                occlusion_texture: texture(self, gltf_material.occlusion_texture, false)?,
+                occlusion_strength: gltf_material.occlusion_strength,
+                emissive_factor: gltf_material.emissive_factor,
+                emissive_texture: texture(self, gltf_material.emissive_texture, true)?,
+            };
+            bound.materials.push(self.create_material(material)?);
+        }
+
+        for gltf_mesh in &model.meshes {
+            let index = bound.meshes.len();
+            bound.meshes.push(Vec::with_capacity(gltf_mesh.primitives.len()));
+            for prim in &gltf_mesh.primitives {
+                let material = *bound.materials.get(prim.material).ok_or(Error::InvalidHandle("model material"))?;
+                let mesh = self.bind_mesh(&prim.mesh, render_pipeline, material)?;
+                bound.meshes[index].push(mesh);
+            }
+        }
+        Ok(())
+    }
+
+    // deletes a bound model's parts, meshes first since they use the materials, which use the
+    // textures. parts that were already deleted by hand, or that are still in use, are skipped
+    pub(in crate::rendering) fn delete_bound_model(&mut self, bound: BoundModel) {
+        for mesh in bound.meshes.into_iter().flatten() {
+            let _ = self.delete_mesh(mesh);
+        }
+        for material in bound.materials {
+            let _ = self.delete_material(material);
+        }
+        for texture in bound.textures {
+            let _ = self.delete_texture(texture);
+        }
+    }
+
+    /// Places one of the model's meshes, returning an instance per primitive.
+    pub fn create_model_instance(&mut self, model: &BoundModel, mesh: usize, instance: ObjectInstance) -> Result<Vec<InstanceHandle>> {
+        model.meshes.get(mesh)
+            .ok_or(Error::InvalidHandle("model mesh"))?
+            .iter()
+            .map(|bound_mesh| self.create_object_instance(*bound_mesh, instance.clone()))
+            .collect()
+    }
+
+    /// Places every object from the model's scene graph where the file put it.
+    pub fn create_model_objects(&mut self, model: &GltfModel, bound: &BoundModel) -> Result<Vec<Vec<InstanceHandle>>> {
+        model.objects.iter()
+            .map(|object| self.create_model_instance(bound, object.mesh, object.instance.clone()))
+            .collect()
+    }
+}
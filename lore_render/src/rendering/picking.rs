@@ -0,0 +1,163 @@
+/*!
+ * finding what's under the cursor. picking happens on the CPU: a ray is shot
+ * from the camera through the cursor, checked against every instance's bounding
+ * sphere first, and only then against the triangles of the instance's mesh,
+ * which bound meshes keep a copy of for this.
+ */
+
+use crate::handle::InstanceHandle;
+use cgmath::{prelude::*, Matrix4, Point3, Vector3, Vector4};
+
+/// What `RenderingInstance::pick` found under the cursor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PickHit {
+    pub instance: InstanceHandle,
+    /// Where the ray hit the instance, in world space
+    pub position: Point3<f32>,
+    /// How far the hit is from the camera's near plane, in world units
+    pub distance: f32,
+}
+
+pub(in crate::rendering) struct Ray {
+    pub origin: Point3<f32>,
+    // not necessarily normalized, so distances along transformed rays still line up
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    /// The ray through a point in normalized device coordinates, from the near plane
+    /// to the far plane. `None` if the camera's matrix can't be inverted.
+    pub fn from_ndc(view_proj: Matrix4<f32>, x: f32, y: f32) -> Option<Self> {
+        let inverse = view_proj.invert()?;
+        // depth goes from 0 at the near plane to 1 at the far plane
+        let unproject = |z: f32| {
+            let point = inverse * Vector4::new(x, y, z, 1.0);
+            Point3::from_homogeneous(point)
+        };
+        let near = unproject(0.0);
+        let far = unproject(1.0);
+        Some(Self {
+            origin: near,
+            direction: (far - near).normalize(),
+        })
+    }
+
+    /// The same ray in the space `matrix` transforms into
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> Self {
+        Self {
+            origin: matrix.transform_point(self.origin),
+            direction: matrix.transform_vector(self.direction),
+        }
+    }
+
+    pub fn at(&self, distance: f32) -> Point3<f32> {
+        self.origin + self.direction * distance
+    }
+
+    /// Whether the ray passes through the sphere anywhere in front of its origin
+    pub fn hits_sphere(&self, center: Point3<f32>, radius: f32) -> bool {
+        let direction = self.direction.normalize();
+        let to_center = center - self.origin;
+        let along = to_center.dot(direction);
+        let closest_squared = to_center.magnitude2() - along * along;
+        closest_squared <= radius * radius
+            && (along >= 0.0 || to_center.magnitude2() <= radius * radius)
+    }
+
+    /// Distance along the ray to the triangle, if it's hit from either side (möller-trumbore)
+    pub fn hits_triangle(&self, a: Point3<f32>, b: Point3<f32>, c: Point3<f32>) -> Option<f32> {
+        // relative to the triangle's size and the ray's length, so tiny and huge meshes pick the same
+        const EPSILON: f32 = 1e-6;
+        let edge_1 = b - a;
+        let edge_2 = c - a;
+        let p = self.direction.cross(edge_2);
+        let determinant = edge_1.dot(p);
+        let scale = self.direction.magnitude() * edge_1.magnitude() * edge_2.magnitude();
+        if determinant.abs() <= EPSILON * scale {
+            return None; // parallel to the triangle, or the triangle has no area
+        }
+        let inverse_determinant = 1.0 / determinant;
+        let to_origin = self.origin - a;
+        let u = to_origin.dot(p) * inverse_determinant;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = to_origin.cross(edge_1);
+        let v = self.direction.dot(q) * inverse_determinant;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = edge_2.dot(q) * inverse_determinant;
+        if distance >= 0.0 {
+            Some(distance)
+        } else {
+            None // behind the origin
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+        Ray {
+            origin: origin.into(),
+            direction: direction.into(),
+        }
+    }
+
+    // in the z = 0 plane, scaled up or down
+    fn triangle(size: f32) -> [Point3<f32>; 3] {
+        [Point3::new(0.0, 0.0, 0.0), Point3::new(size, 0.0, 0.0), Point3::new(0.0, size, 0.0)]
+    }
+
+    #[test]
+    fn hits_triangle_from_both_sides() {
+        let [a, b, c] = triangle(1.0);
+        assert_eq!(ray([0.25, 0.25, 5.0], [0.0, 0.0, -1.0]).hits_triangle(a, b, c), Some(5.0));
+        assert_eq!(ray([0.25, 0.25, -2.0], [0.0, 0.0, 1.0]).hits_triangle(a, b, c), Some(2.0));
+        // distances are in units of the direction's length
+        assert_eq!(ray([0.25, 0.25, 5.0], [0.0, 0.0, -2.0]).hits_triangle(a, b, c), Some(2.5));
+    }
+
+    #[test]
+    fn misses_beside_the_triangle() {
+        let [a, b, c] = triangle(1.0);
+        assert_eq!(ray([0.75, 0.75, 5.0], [0.0, 0.0, -1.0]).hits_triangle(a, b, c), None);
+        assert_eq!(ray([-0.1, 0.5, 5.0], [0.0, 0.0, -1.0]).hits_triangle(a, b, c), None);
+        assert_eq!(ray([0.5, -0.1, 5.0], [0.0, 0.0, -1.0]).hits_triangle(a, b, c), None);
+    }
+
+    #[test]
+    fn misses_when_parallel() {
+        let [a, b, c] = triangle(1.0);
+        assert_eq!(ray([-1.0, 0.25, 0.0], [1.0, 0.0, 0.0]).hits_triangle(a, b, c), None);
+        assert_eq!(ray([-1.0, 0.25, 1.0], [1.0, 0.0, 0.0]).hits_triangle(a, b, c), None);
+    }
+
+    #[test]
+    fn misses_behind_the_origin() {
+        let [a, b, c] = triangle(1.0);
+        assert_eq!(ray([0.25, 0.25, 5.0], [0.0, 0.0, 1.0]).hits_triangle(a, b, c), None);
+    }
+
+    #[test]
+    fn misses_degenerate_triangles() {
+        let a = Point3::new(0.0, 0.0, 0.0);
+        let b = Point3::new(1.0, 1.0, 0.0);
+        assert_eq!(ray([0.5, 0.5, 5.0], [0.0, 0.0, -1.0]).hits_triangle(a, b, b), None);
+        assert_eq!(ray([0.5, 0.5, 5.0], [0.0, 0.0, -1.0]).hits_triangle(a, a, a), None);
+    }
+
+    #[test]
+    fn hits_tiny_and_huge_triangles() {
+        for size in [1e-4, 1e4] {
+            let [a, b, c] = triangle(size);
+            let hit = ray([size / 4.0, size / 4.0, size], [0.0, 0.0, -1.0]).hits_triangle(a, b, c);
+            assert_eq!(hit, Some(size), "size {}", size);
+            let miss = ray([size, size, size], [0.0, 0.0, -1.0]).hits_triangle(a, b, c);
+            assert_eq!(miss, None, "size {}", size);
+        }
+    }
+}
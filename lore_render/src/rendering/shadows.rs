@@ -0,0 +1,313 @@
+/*!
+ * shadow maps for directional and spot lights. every shadow-casting light gets
+ * a layer of one depth texture array, which a depth-only pass renders the
+ * shadow casters into before the cameras draw. the default shader then compares
+ * against it (with PCF) to find out whether a point can see the light.
+ *
+ * the texture array and its comparison sampler are bound along with the lights
+ * (group 2, bindings 1 and 2), and every light's matrix into its shadow map is
+ * in `LightsUniform::shadow_matrices`.
+ */
+
+use crate::{
+    mesh::Vertex,
+    rendering::{
+        camera::OPENGL_TO_WGPU_MATRIX,
+        depth::DEPTH_FORMAT,
+        engine::RawObjectInstance,
+    },
+};
+use cgmath::{prelude::*, Deg, Matrix4, Point3, Vector3};
+use wgpu::util::DeviceExt;
+
+/// The most lights that can cast shadows at the same time. Has to match the default shader.
+pub const MAX_SHADOW_MAPS: usize = 4;
+/// Width and height of every shadow map. Has to match the default shader.
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+/// How far in front of the cameras directional shadows reach at most
+pub(in crate::rendering) const DIRECTIONAL_SHADOW_DISTANCE: f32 = 50.0;
+
+pub(in crate::rendering) struct ShadowMaps {
+    #[allow(dead_code)] // the views are what gets used, but the texture has to outlive them
+    texture: wgpu::Texture,
+    // the whole array, for sampling
+    pub array_view: wgpu::TextureView,
+    // one view per layer, for rendering into
+    pub layer_views: Vec<wgpu::TextureView>,
+    pub sampler: wgpu::Sampler,
+    pub pipeline: wgpu::RenderPipeline,
+    // the light matrix each layer is rendered with
+    pass_buffers: Vec<wgpu::Buffer>,
+    pub pass_bind_groups: Vec<wgpu::BindGroup>,
+    // how many layers have a light this frame
+    pub in_use: usize,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: SHADOW_MAP_SIZE,
+                height: SHADOW_MAP_SIZE,
+                depth_or_array_layers: MAX_SHADOW_MAPS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("Shadow Maps"),
+        });
+        let array_view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layer_views = (0..MAX_SHADOW_MAPS as u32)
+            .map(|layer| texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            }))
+            .collect();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let pass_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }
+            ],
+            label: Some("shadow_pass_bind_group_layout"),
+        });
+        let pass_buffers = (0..MAX_SHADOW_MAPS)
+            .map(|_| device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("shadow_pass_buffer"),
+                contents: bytemuck::cast_slice(&[[[0.0f32; 4]; 4]]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }))
+            .collect::<Vec<_>>();
+        let pass_bind_groups = pass_buffers.iter()
+            .map(|buffer| device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &pass_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    }
+                ],
+                label: Some("shadow_pass_bind_group"),
+            }))
+            .collect();
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow_pipeline_layout"),
+            bind_group_layouts: &[&pass_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("shadow_shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../../shaders/shadow_shader.wgsl").into()),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[
+                    Vertex::desc(),
+                    RawObjectInstance::desc(),
+                ],
+            },
+            // depth only
+            fragment: None,
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                // pushes depth away from the light a little, so surfaces don't shadow themselves
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+        });
+
+        Self {
+            texture,
+            array_view,
+            layer_views,
+            sampler,
+            pipeline,
+            pass_buffers,
+            pass_bind_groups,
+            in_use: 0,
+        }
+    }
+
+    /// Sets the matrix every layer is rendered with. Layers past the end of
+    /// `matrices` are left alone and don't get rendered.
+    pub fn write_matrices(&mut self, queue: &wgpu::Queue, matrices: &[Matrix4<f32>]) {
+        for (buffer, matrix) in self.pass_buffers.iter().zip(matrices) {
+            let raw: [[f32; 4]; 4] = (*matrix).into();
+            queue.write_buffer(buffer, 0, bytemuck::cast_slice(&[raw]));
+        }
+        self.in_use = matrices.len().min(MAX_SHADOW_MAPS);
+    }
+}
+
+/// A center and a radius
+pub(in crate::rendering) type Sphere = (Point3<f32>, f32);
+
+/// Where a directional light's shadow map has to reach. Shadows only fall where
+/// the casters are, seen from the light, and only matter where the cameras look,
+/// so the map covers whichever of those is smaller. In depth it always has to
+/// reach every caster.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(in crate::rendering) struct ShadowBounds {
+    pub casters: Sphere,
+    pub receivers: Sphere,
+}
+
+impl ShadowBounds {
+    /// `view` is a sphere around what the cameras see, if there are any
+    pub fn new(casters: Sphere, view: Option<Sphere>) -> Self {
+        let receivers = match view {
+            Some(view) if view.1 < casters.1 => view,
+            _ => casters,
+        };
+        Self {
+            casters,
+            receivers,
+        }
+    }
+}
+
+/// A sphere around all of the spheres, `None` if there aren't any
+pub(in crate::rendering) fn enclosing_sphere(spheres: impl IntoIterator<Item = Sphere>) -> Option<Sphere> {
+    let mut spheres = spheres.into_iter();
+    let (first_center, first_radius) = spheres.next()?;
+    let extent = |(center, radius): Sphere| {
+        let r = Vector3::new(radius, radius, radius);
+        (center - r, center + r)
+    };
+    let (min, max) = spheres.fold(extent((first_center, first_radius)), |(min, max), sphere| {
+        let (lo, hi) = extent(sphere);
+        (
+            Point3::new(min.x.min(lo.x), min.y.min(lo.y), min.z.min(lo.z)),
+            Point3::new(max.x.max(hi.x), max.y.max(hi.y), max.z.max(hi.z)),
+        )
+    });
+    Some((min.midpoint(max), min.distance(max) / 2.0))
+}
+
+/// Matrix into the shadow map of a directional light, see `ShadowBounds`.
+pub(in crate::rendering) fn directional_shadow_matrix(direction: Vector3<f32>, bounds: &ShadowBounds) -> Matrix4<f32> {
+    let direction = direction.normalize();
+    let (center, radius) = bounds.receivers;
+    let radius = radius.max(0.01);
+    // how far the map reaches towards the light and away from it, measured from the center
+    let (caster_center, caster_radius) = bounds.casters;
+    let caster_offset = (caster_center - center).dot(direction);
+    let towards_light = radius.max(caster_radius - caster_offset);
+    let away_from_light = radius.max(caster_radius + caster_offset);
+    let eye = center - direction * (towards_light + radius);
+    let view = Matrix4::look_at_rh(eye, center, up_for(direction));
+    let proj = cgmath::ortho(-radius, radius, -radius, radius, radius, radius + towards_light + away_from_light);
+    OPENGL_TO_WGPU_MATRIX * proj * view
+}
+
+/// Matrix into the shadow map of a spot light, covering its whole cone.
+pub(in crate::rendering) fn spot_shadow_matrix(position: Point3<f32>, direction: Vector3<f32>, outer_angle: Deg<f32>, range: f32) -> Matrix4<f32> {
+    let direction = direction.normalize();
+    let view = Matrix4::look_at_rh(position, position + direction, up_for(direction));
+    let fovy = Deg((outer_angle.0 * 2.0).clamp(1.0, 170.0));
+    let proj = cgmath::perspective(fovy, 1.0, 0.05, range.max(0.1));
+    OPENGL_TO_WGPU_MATRIX * proj * view
+}
+
+// any up vector works for a light, as long as it isn't parallel to the direction
+fn up_for(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // where a point lands in the shadow map: x and y in -1..1, depth in 0..1
+    fn project(matrix: Matrix4<f32>, point: Point3<f32>) -> Vector3<f32> {
+        let clip = matrix * point.to_homogeneous();
+        clip.truncate() / clip.w
+    }
+
+    fn inside(projected: Vector3<f32>) -> bool {
+        projected.x.abs() <= 1.0 && projected.y.abs() <= 1.0 && (0.0..=1.0).contains(&projected.z)
+    }
+
+    #[test]
+    fn enclosing_sphere_contains_every_sphere() {
+        assert_eq!(enclosing_sphere(Vec::new()), None);
+        let spheres = [(Point3::new(0.0, 0.0, 0.0), 1.0), (Point3::new(10.0, 0.0, 0.0), 2.0)];
+        let (center, radius) = enclosing_sphere(spheres).unwrap();
+        for (sphere_center, sphere_radius) in spheres {
+            assert!(center.distance(sphere_center) + sphere_radius <= radius + 1e-4);
+        }
+    }
+
+    #[test]
+    fn smaller_view_narrows_the_map() {
+        let casters = (Point3::new(0.0, 0.0, 0.0), 100.0);
+        let view = (Point3::new(5.0, 0.0, 0.0), 10.0);
+        assert_eq!(ShadowBounds::new(casters, Some(view)).receivers, view);
+        assert_eq!(ShadowBounds::new(casters, None).receivers, casters);
+        assert_eq!(ShadowBounds::new((Point3::new(0.0, 0.0, 0.0), 1.0), Some(view)).receivers.1, 1.0);
+    }
+
+    #[test]
+    fn directional_map_reaches_casters_between_the_light_and_the_view() {
+        let direction = Vector3::new(0.0, -1.0, 0.0);
+        // a tall caster high above the small area the camera looks at
+        let bounds = ShadowBounds::new((Point3::new(0.0, 20.0, 0.0), 25.0), Some((Point3::new(0.0, 0.0, 0.0), 5.0)));
+        let matrix = directional_shadow_matrix(direction, &bounds);
+        assert!(inside(project(matrix, Point3::new(0.0, 44.0, 0.0))));
+        assert!(inside(project(matrix, Point3::new(4.0, -4.0, 0.0))));
+        // the map only covers the view sideways
+        assert!(!inside(project(matrix, Point3::new(20.0, 0.0, 0.0))));
+    }
+}
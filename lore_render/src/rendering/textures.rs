@@ -0,0 +1,314 @@
+/*!
+ * how textures are stored and sampled, and generating their mip chains.
+ *
+ * mip levels are rendered on the GPU at upload: every level is drawn from the
+ * one above it with a linear filter, which halves it in both directions.
+ */
+
+#[cfg(feature = "ktx2")]
+use crate::asset_loading::ktx2::BlockFormat;
+use std::collections::HashMap;
+
+/// How to pick a color between texels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// The closest texel, for a crisp, pixelated look
+    Nearest,
+    /// Blend between the surrounding texels
+    Linear,
+}
+
+/// What happens to texture coordinates outside of 0..1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressMode {
+    /// The texture tiles
+    Repeat,
+    /// The texture tiles, flipped every other time
+    MirrorRepeat,
+    /// The edge texels stretch out forever
+    ClampToEdge,
+}
+
+/// Settings for `RenderingInstance::create_texture`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TextureOptions {
+    /// Filter for when the texture is drawn larger than it is
+    pub mag_filter: Filter,
+    /// Filter for when the texture is drawn smaller than it is
+    pub min_filter: Filter,
+    /// Filter between mip levels
+    pub mipmap_filter: Filter,
+    pub address_mode_u: AddressMode,
+    pub address_mode_v: AddressMode,
+    /// Anisotropic filtering for textures seen at steep angles. 1 turns it off,
+    /// anything else is rounded down to 2, 4, 8 or 16. Only used if every filter is `Linear`.
+    pub anisotropy: u8,
+    /// Color textures are stored in sRGB. Data like normal maps or metallic-roughness
+    /// maps must not be, or their values get converted on the way into the shader.
+    pub srgb: bool,
+    /// Generate a full mip chain, so the texture doesn't shimmer in the distance
+    pub mipmaps: bool,
+}
+
+impl Default for TextureOptions {
+    /// Smoothly filtered, repeating, mipmapped color texture
+    fn default() -> Self {
+        Self {
+            mag_filter: Filter::Linear,
+            min_filter: Filter::Linear,
+            mipmap_filter: Filter::Linear,
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            anisotropy: 1,
+            srgb: true,
+            mipmaps: true,
+        }
+    }
+}
+
+impl TextureOptions {
+    /// Like the default, but for data textures that aren't stored in sRGB
+    pub fn linear() -> Self {
+        Self {
+            srgb: false,
+            ..Self::default()
+        }
+    }
+
+    /// Nearest filtering and no mipmaps, for pixel art
+    pub fn pixelated() -> Self {
+        Self {
+            mag_filter: Filter::Nearest,
+            min_filter: Filter::Nearest,
+            mipmap_filter: Filter::Nearest,
+            mipmaps: false,
+            ..Self::default()
+        }
+    }
+
+    pub(in crate::rendering) fn format(&self) -> wgpu::TextureFormat {
+        if self.srgb {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Rgba8Unorm
+        }
+    }
+
+    /// How many mip levels a texture of the given size gets
+    pub(in crate::rendering) fn mip_level_count(&self, width: u32, height: u32) -> u32 {
+        if self.mipmaps {
+            32 - width.max(height).leading_zeros()
+        } else {
+            1
+        }
+    }
+
+    pub(in crate::rendering) fn sampler_descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        let all_linear = self.mag_filter == Filter::Linear
+            && self.min_filter == Filter::Linear
+            && self.mipmap_filter == Filter::Linear;
+        let anisotropy = match self.anisotropy {
+            16..=u8::MAX => 16,
+            8..=15 => 8,
+            4..=7 => 4,
+            2..=3 => 2,
+            _ => 1,
+        };
+        wgpu::SamplerDescriptor {
+            address_mode_u: self.address_mode_u.into(),
+            address_mode_v: self.address_mode_v.into(),
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: self.mag_filter.into(),
+            min_filter: self.min_filter.into(),
+            mipmap_filter: self.mipmap_filter.into(),
+            anisotropy_clamp: if all_linear && anisotropy > 1 {
+                std::num::NonZeroU8::new(anisotropy)
+            } else {
+                None
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Filter> for wgpu::FilterMode {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => wgpu::FilterMode::Nearest,
+            Filter::Linear => wgpu::FilterMode::Linear,
+        }
+    }
+}
+
+impl From<AddressMode> for wgpu::AddressMode {
+    fn from(mode: AddressMode) -> Self {
+        match mode {
+            AddressMode::Repeat => wgpu::AddressMode::Repeat,
+            AddressMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+            AddressMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        }
+    }
+}
+
+/// The wgpu format for a block compression format
+#[cfg(feature = "ktx2")]
+pub(in crate::rendering) fn block_texture_format(format: BlockFormat, srgb: bool) -> wgpu::TextureFormat {
+    use wgpu::TextureFormat::*;
+    match (format, srgb) {
+        (BlockFormat::Bc1, false) => Bc1RgbaUnorm,
+        (BlockFormat::Bc1, true) => Bc1RgbaUnormSrgb,
+        (BlockFormat::Bc3, false) => Bc3RgbaUnorm,
+        (BlockFormat::Bc3, true) => Bc3RgbaUnormSrgb,
+        (BlockFormat::Bc7, false) => Bc7RgbaUnorm,
+        (BlockFormat::Bc7, true) => Bc7RgbaUnormSrgb,
+        (BlockFormat::Etc2Rgb8, false) => Etc2Rgb8Unorm,
+        (BlockFormat::Etc2Rgb8, true) => Etc2Rgb8UnormSrgb,
+        (BlockFormat::Etc2Rgba8, false) => Etc2Rgba8Unorm,
+        (BlockFormat::Etc2Rgba8, true) => Etc2Rgba8UnormSrgb,
+        // single and dual channel formats hold data, never color
+        (BlockFormat::Bc4, _) => Bc4RUnorm,
+        (BlockFormat::Bc5, _) => Bc5RgUnorm,
+        (BlockFormat::EacR11, _) => EacR11Unorm,
+        (BlockFormat::EacRg11, _) => EacRg11Unorm,
+    }
+}
+
+/// Fills in the mip levels of textures, with a blit pipeline per texture format.
+pub(in crate::rendering) struct MipmapGenerator {
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    shader: wgpu::ShaderModule,
+    // built the first time a format needs one
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("mipmap_bind_group_layout"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("mipmap_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("blit_shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../../shaders/blit_shader.wgsl").into()),
+        });
+
+        Self {
+            bind_group_layout,
+            sampler,
+            shader,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Renders every mip level after the first from the one before it. The texture
+    /// needs `RENDER_ATTACHMENT` and `TEXTURE_BINDING` usage.
+    pub fn generate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture, format: wgpu::TextureFormat, mip_level_count: u32) {
+        if mip_level_count < 2 {
+            return;
+        }
+        self.build_pipeline(device, format);
+        let pipeline = &self.pipelines[&format];
+        let views = (0..mip_level_count)
+            .map(|level| texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("mip_view"),
+                base_mip_level: level,
+                mip_level_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            }))
+            .collect::<Vec<_>>();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        for level in 1..mip_level_count as usize {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&views[level - 1]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+                label: None,
+            });
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &views[level],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    // the pipeline for a format is only built the first time it's needed
+    fn build_pipeline(&mut self, device: &wgpu::Device, format: wgpu::TextureFormat) {
+        let (bind_group_layout, shader) = (&self.bind_group_layout, &self.shader);
+        self.pipelines.entry(format).or_insert_with(|| {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("mipmap_pipeline_layout"),
+                bind_group_layouts: &[bind_group_layout],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("mipmap_pipeline"),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point: "fs_main",
+                    targets: &[format.into()],
+                }),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+                primitive: wgpu::PrimitiveState::default(),
+            })
+        });
+    }
+}
@@ -0,0 +1,211 @@
+/*
+ * helpers for golden-image (snapshot) tests of rendering output.
+ *
+ * a test builds a scene on a headless `RenderingInstance` with the usual
+ * `bind_mesh`/`create_object_instance` calls, captures a frame, and compares it
+ * to a PNG checked into the repo:
+ *
+ *     let frame = testing::render_scene(256, 256, |ri| {
+ *         let pl = ri.create_default_render_pipeline().unwrap();
+ *         let mesh = ri.bind_mesh(&cube, pl, ri.default_material()).unwrap();
+ *         ri.create_object_instance(mesh, ObjectInstance::from_position(0.0, 0.0, 0.0)).unwrap();
+ *     });
+ *     testing::assert_matches_golden(&frame, "tests/golden/cube.png", 2);
+ *
+ * golden images are only written when `LORE_UPDATE_GOLDEN` is set in the
+ * environment, then the frame becomes the new golden image. otherwise a missing
+ * golden image fails the test, so a typo in a path can't quietly pass. when a
+ * comparison fails, `<name>.actual.png` and `<name>.diff.png` are written next
+ * to the golden image so the failure can be inspected.
+ *
+ * machines without any GPU adapter can't render at all, so tests built on
+ * `try_render_scene` skip themselves there. set `LORE_REQUIRE_GPU` (as CI should)
+ * to make a missing adapter fail the test instead.
+ *
+ * this module is only built with the `testing` feature (and for lore_render's
+ * own tests), so add it to your dev-dependencies:
+ *
+ *     [dev-dependencies]
+ *     lore_render = { path = "../lore_render", features = ["testing"] }
+ */
+
+use crate::{RenderingInstance, Result};
+use image::{Rgba, RgbaImage};
+use std::path::{Path, PathBuf};
+
+/// Set this environment variable to overwrite golden images with the current output.
+pub const UPDATE_GOLDEN_VAR: &str = "LORE_UPDATE_GOLDEN";
+
+/// Set this environment variable to fail, rather than skip, tests that find no GPU adapter.
+pub const REQUIRE_GPU_VAR: &str = "LORE_REQUIRE_GPU";
+
+/// The result of comparing two images that didn't match.
+pub struct ImageDiff {
+    /// Number of pixels where some channel differed by more than the tolerance
+    pub mismatched_pixels: usize,
+    /// Largest difference seen in any single channel
+    pub max_difference: u8,
+    /// Mismatched pixels in red over a faded copy of the expected image
+    pub diff_image: RgbaImage,
+}
+
+/// Renders one frame of a scene on a fresh headless instance.
+/// `build` sets up the scene, the frame is captured right after.
+pub fn render_scene<F>(width: u32, height: u32, build: F) -> RgbaImage
+where
+    F: FnOnce(&mut RenderingInstance),
+{
+    try_render_scene(width, height, build).expect("Failed to render headless scene")
+}
+
+/// Like `render_scene`, but returns the error instead of panicking, so tests can
+/// skip themselves on machines without any GPU adapter (`Error::Gpu`).
+pub fn try_render_scene<F>(width: u32, height: u32, build: F) -> Result<RgbaImage>
+where
+    F: FnOnce(&mut RenderingInstance),
+{
+    let mut rendering_instance = RenderingInstance::new_headless(width, height)?;
+    build(&mut rendering_instance);
+    rendering_instance.draw_to_image()
+}
+
+/// Compares two images channel by channel. Returns `None` if every channel of
+/// every pixel is within `tolerance` of the expected value.
+/// Images of different sizes never match.
+pub fn compare_images(actual: &RgbaImage, expected: &RgbaImage, tolerance: u8) -> Option<ImageDiff> {
+    if actual.dimensions() != expected.dimensions() {
+        let (width, height) = actual.dimensions();
+        return Some(ImageDiff {
+            mismatched_pixels: (width * height) as usize,
+            max_difference: u8::MAX,
+            diff_image: RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 255])),
+        });
+    }
+
+    let mut mismatched_pixels = 0;
+    let mut max_difference = 0;
+    let mut diff_image = RgbaImage::new(expected.width(), expected.height());
+    for (x, y, expected_px) in expected.enumerate_pixels() {
+        let actual_px = actual.get_pixel(x, y);
+        let difference = actual_px.0.iter()
+            .zip(expected_px.0.iter())
+            .map(|(a, e)| a.abs_diff(*e))
+            .max()
+            .unwrap_or(0);
+        max_difference = max_difference.max(difference);
+
+        if difference > tolerance {
+            mismatched_pixels += 1;
+            diff_image.put_pixel(x, y, Rgba([255, 0, 0, 255]));
+        } else {
+            let [r, g, b, _] = expected_px.0;
+            let luma = ((r as u32 + g as u32 + b as u32) / 3) as u8;
+            diff_image.put_pixel(x, y, Rgba([luma / 4, luma / 4, luma / 4, 255]));
+        }
+    }
+
+    if mismatched_pixels == 0 {
+        None
+    } else {
+        Some(ImageDiff {
+            mismatched_pixels,
+            max_difference,
+            diff_image,
+        })
+    }
+}
+
+/// Panics if `actual` doesn't match the PNG at `golden_path` within `tolerance`
+/// per channel. See the module docs for how golden images get created and updated.
+pub fn assert_matches_golden<P: AsRef<Path>>(actual: &RgbaImage, golden_path: P, tolerance: u8) {
+    let golden_path = golden_path.as_ref();
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        if let Some(parent) = golden_path.parent() {
+            std::fs::create_dir_all(parent).expect("Failed to create golden image directory");
+        }
+        actual.save(golden_path).expect("Failed to write golden image");
+        return;
+    }
+    if !golden_path.exists() {
+        panic!(
+            "Golden image {} doesn't exist. Run the test with {}=1 to create it",
+            golden_path.display(),
+            UPDATE_GOLDEN_VAR,
+        );
+    }
+
+    let expected = image::open(golden_path)
+        .unwrap_or_else(|e| panic!("Failed to load golden image {}: {}", golden_path.display(), e))
+        .to_rgba8();
+    if let Some(diff) = compare_images(actual, &expected, tolerance) {
+        let actual_path = sibling_path(golden_path, "actual");
+        let diff_path = sibling_path(golden_path, "diff");
+        actual.save(&actual_path).expect("Failed to write actual image");
+        diff.diff_image.save(&diff_path).expect("Failed to write diff image");
+        panic!(
+            "Rendered image doesn't match {}: {} pixels differ by more than {} (max difference {}). See {} and {}",
+            golden_path.display(),
+            diff.mismatched_pixels,
+            tolerance,
+            diff.max_difference,
+            actual_path.display(),
+            diff_path.display(),
+        );
+    }
+}
+
+// golden/cube.png -> golden/cube.<suffix>.png
+fn sibling_path(golden_path: &Path, suffix: &str) -> PathBuf {
+    let stem = golden_path.file_stem().and_then(|s| s.to_str()).unwrap_or("golden");
+    golden_path.with_file_name(format!("{}.{}.png", stem, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba([value, value, value, 255]))
+    }
+
+    #[test]
+    fn differences_up_to_the_tolerance_match() {
+        assert!(compare_images(&solid(4, 4, 100), &solid(4, 4, 100), 0).is_none());
+        assert!(compare_images(&solid(4, 4, 102), &solid(4, 4, 100), 2).is_none());
+        assert!(compare_images(&solid(4, 4, 98), &solid(4, 4, 100), 2).is_none());
+
+        let diff = compare_images(&solid(4, 4, 103), &solid(4, 4, 100), 2).unwrap();
+        assert_eq!(diff.mismatched_pixels, 16);
+        assert_eq!(diff.max_difference, 3);
+    }
+
+    #[test]
+    fn different_sizes_never_match() {
+        let diff = compare_images(&solid(4, 2, 100), &solid(4, 4, 100), u8::MAX).unwrap();
+        assert_eq!(diff.mismatched_pixels, 8);
+        assert_eq!(diff.max_difference, u8::MAX);
+        assert_eq!(diff.diff_image.dimensions(), (4, 2));
+    }
+
+    #[test]
+    fn diff_image_marks_mismatched_pixels() {
+        let expected = solid(2, 2, 200);
+        let mut actual = expected.clone();
+        actual.put_pixel(1, 0, Rgba([200, 0, 200, 255]));
+
+        let diff = compare_images(&actual, &expected, 2).unwrap();
+        assert_eq!(diff.mismatched_pixels, 1);
+        assert_eq!(diff.max_difference, 200);
+        assert_eq!(*diff.diff_image.get_pixel(1, 0), Rgba([255, 0, 0, 255]));
+        // matching pixels are the expected image, darkened
+        assert_eq!(*diff.diff_image.get_pixel(0, 0), Rgba([50, 50, 50, 255]));
+        assert_eq!(*diff.diff_image.get_pixel(1, 1), Rgba([50, 50, 50, 255]));
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't exist")]
+    fn missing_golden_images_fail() {
+        let path = std::env::temp_dir().join("lore_render_missing_golden").join("missing.png");
+        assert_matches_golden(&solid(2, 2, 0), path, 0);
+    }
+}
@@ -0,0 +1,122 @@
+use lore_render::{
+    testing, Error, RenderingInstance, Viewport, Mesh, Vertex, ObjectInstance, DirectionalLight,
+    cgmath::{Point3, Vector3},
+};
+use image::RgbaImage;
+
+// renders a scene, or returns None (and says so) when there's no GPU adapter to render with.
+// with LORE_REQUIRE_GPU set a missing adapter fails the test, so CI can't pass by skipping everything
+fn render<F: FnOnce(&mut RenderingInstance)>(width: u32, height: u32, build: F) -> Option<RgbaImage> {
+    match testing::try_render_scene(width, height, build) {
+        Ok(frame) => Some(frame),
+        Err(Error::Gpu(e)) if std::env::var_os(testing::REQUIRE_GPU_VAR).is_none() => {
+            eprintln!("skipping golden image test, no GPU adapter: {}", e);
+            None
+        },
+        Err(e) => panic!("Failed to render scene: {}", e),
+    }
+}
+
+#[test]
+fn empty_scene_is_the_clear_color() {
+    if let Some(frame) = render(64, 64, |_| {}) {
+        testing::assert_matches_golden(&frame, "tests/golden/empty_scene.png", 2);
+    }
+}
+
+#[test]
+fn camera_with_an_empty_viewport_still_clears_the_frame() {
+    let frame = render(64, 64, |ri| {
+        let viewport = Viewport { x: 0.0, y: 0.0, width: 0.0, height: 0.0 };
+        ri.set_camera_viewport(ri.main_camera(), viewport).unwrap();
+    });
+    if let Some(frame) = frame {
+        testing::assert_matches_golden(&frame, "tests/golden/empty_scene.png", 2);
+    }
+}
+
+#[test]
+fn frame_without_a_camera_is_still_cleared() {
+    let frame = render(64, 64, |ri| {
+        let texture = ri.create_render_target(32, 32).unwrap();
+        ri.set_camera_target(ri.main_camera(), Some(texture)).unwrap();
+    });
+    if let Some(frame) = frame {
+        testing::assert_matches_golden(&frame, "tests/golden/empty_scene.png", 2);
+    }
+}
+
+#[test]
+fn textures_larger_than_the_gpu_allows_are_errors() {
+    // wider than any GPU's textures go
+    let too_wide = 1 << 20;
+    render(64, 64, |ri| {
+        assert!(matches!(ri.create_render_target(too_wide, 1), Err(Error::Gpu(_))));
+        let image = image::DynamicImage::new_rgba8(too_wide, 1);
+        assert!(matches!(ri.create_texture(image, Default::default()), Err(Error::Gpu(_))));
+    });
+}
+
+// unit cube centered on the origin, 4 vertices per face so every face gets its own normal
+fn cube() -> Mesh {
+    let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        // normal, u, v
+        ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+        ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+        ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ];
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for (normal, u, v) in faces {
+        let first = vertices.len() as u32;
+        for (su, sv) in [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)] {
+            let position = [0, 1, 2].map(|i| normal[i] * 0.5 + u[i] * su + v[i] * sv);
+            vertices.push(Vertex { position, normal, tex_coords: [su + 0.5, 0.5 - sv] });
+        }
+        indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+    }
+    Mesh::new(vertices, indices)
+}
+
+// a rotated, scaled cube with the default material, lit by one directional light
+fn render_lit_cube() -> Option<RgbaImage> {
+    render(64, 64, |ri| {
+        let camera = ri.main_camera();
+        ri.set_camera_transform(camera, Some(Point3::new(0.0, 1.5, 4.0)), Some(Point3::new(0.0, 0.0, 0.0)), None).unwrap();
+        ri.add_light(DirectionalLight {
+            direction: Vector3::new(-0.5, -1.0, -0.3),
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            cast_shadows: false,
+        }).unwrap();
+
+        let pipeline = ri.create_default_render_pipeline().unwrap();
+        let mesh = ri.bind_mesh(&cube(), pipeline, ri.default_material()).unwrap();
+        let instance = ObjectInstance::from_position(0.0, 0.25, 0.0)
+            .with_angle(Vector3::new(0.0, 1.0, 0.0), 30.0)
+            .with_uniform_scale(1.5);
+        ri.create_object_instance(mesh, instance).unwrap();
+    })
+}
+
+#[test]
+fn lit_cube_covers_the_middle_of_the_frame() {
+    if let Some(frame) = render_lit_cube() {
+        let background = frame.get_pixel(0, 0).0;
+        let middle = frame.get_pixel(32, 32).0;
+        let difference = middle.iter().zip(background.iter()).map(|(m, b)| m.abs_diff(*b)).max().unwrap();
+        assert!(difference > 2, "the cube wasn't drawn: the middle of the frame is the clear color {:?}", middle);
+        assert_eq!(frame.get_pixel(63, 63).0, background);
+    }
+}
+
+#[test]
+#[ignore = "tests/golden/lit_cube.png has to be created on a machine with a GPU first, with LORE_UPDATE_GOLDEN=1"]
+fn lit_cube_matches_golden() {
+    if let Some(frame) = render_lit_cube() {
+        testing::assert_matches_golden(&frame, "tests/golden/lit_cube.png", 2);
+    }
+}
@@ -1,3 +1,6 @@
+// the language is still a sketch: most of the tree and the vm are not wired up yet
+#![allow(dead_code, unused_variables, clippy::result_large_err)]
+
 extern crate pest;
 #[macro_use]
 extern crate pest_derive;
@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::mem;
 
 use crate::AST;
 
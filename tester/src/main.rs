@@ -1,16 +1,18 @@
 use lore_render::{
     ObjectInstance,
     InputEvent,
+    InstanceHandle,
+    DirectionalLight,
 };
 use lore_render::{
-    cgmath::{*, prelude::*},
+    cgmath::*,
     RenderingInstance,
     VirtualKeyCode, ElementState,
 };
 
 struct State {
-    cube_a: (usize, usize),
-    cube_b: (usize, usize),
+    cube_a: InstanceHandle,
+    cube_b: InstanceHandle,
 }
 
 pub fn main() {
@@ -22,22 +24,29 @@ pub fn main() {
 }
 
 fn setup(rendering_instance: &mut RenderingInstance) -> State {
-    let pl = rendering_instance.create_default_render_pipeline();
+    let pl = rendering_instance.create_default_render_pipeline().unwrap();
+    rendering_instance.add_light(DirectionalLight {
+        direction: Vector3::new(-0.5, 1.0, -0.5),
+        color: [1.0, 1.0, 1.0],
+        intensity: 1.0,
+        cast_shadows: true,
+    }).unwrap();
 
     let cube_mesh = {
-        let mesh_data = lore_render::Mesh::from_gltf("assets/cube.gltf");
-        rendering_instance.bind_mesh(&mesh_data, pl, None)
+        let model = lore_render::asset_loading::meshes::load_gltf("assets/cube.gltf").unwrap();
+        let mesh_data = &model.meshes[0].primitives[0].mesh;
+        rendering_instance.bind_mesh(mesh_data, pl, rendering_instance.default_material()).unwrap()
     };
 
     let cube_a = rendering_instance.create_object_instance(
         cube_mesh,
         ObjectInstance::from_position(-0.5, 0.0, 0.0),
-    );
+    ).unwrap();
     let cube_b = rendering_instance.create_object_instance(
         cube_mesh,
         ObjectInstance::from_position(3.0, 0.0, 0.0)
             .with_angle(Vector3::unit_z(), 45.0),
-    );
+    ).unwrap();
 
     State {
         cube_a, cube_b,
@@ -45,7 +54,7 @@ fn setup(rendering_instance: &mut RenderingInstance) -> State {
     }
 }
 
-fn update(rendering_instance: &mut RenderingInstance, state: &mut State) {
+fn update(_rendering_instance: &mut RenderingInstance, _state: &mut State) {
 
 }
 
@@ -56,14 +65,17 @@ fn input(rendering_instance: &mut RenderingInstance, state: &mut State, input: I
                 if keycode == VirtualKeyCode::Space && key_input.state == ElementState::Pressed {
                     rendering_instance.modify_instance(state.cube_a, |inst| {
                         inst.rotation = inst.rotation * Quaternion::<f32>::from_axis_angle(Vector3::unit_y(), Deg(5.0));
-                    });
+                    }).unwrap();
                     rendering_instance.modify_instance(state.cube_b, |inst| {
                         inst.rotation = inst.rotation * Quaternion::<f32>::from_axis_angle(Vector3::unit_x(), Deg(3.0));
-                    });
+                    }).unwrap();
                 }
             }
         },
-        InputEvent::Mouse(button_state, button) => {
+        InputEvent::Mouse(..) => {
+
+        },
+        InputEvent::MouseLocation(..) => {
 
         },
     }